@@ -0,0 +1,713 @@
+use std::fmt::Display;
+use std::io::BufRead;
+use serde_derive::{Deserialize, Serialize};
+use time::{Date, Duration, UtcDateTime};
+use crate::coords::StoreCoords;
+use crate::free_map::RunConstraints;
+use crate::warehouse::{product_run, AbcThresholds, Product, ProductCategory, VelocityClass, Warehouse, WarehouseAllocator, WarehouseEntry};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorClosestFirst;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorClosestFirstEfficient;
+
+impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorClosestFirst {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let mut oversized_count = if let ProductCategory::Oversized { zone_count } = product.quality() {
+            *zone_count
+        } else { 0 };
+        let max_row = if let ProductCategory::Fragile { max_row, .. } = product.quality() {
+            Some(*max_row)
+        } else { None };
+        
+        for (i,row) in warehouse.store().iter().enumerate() {
+            if let Some(max_row) = max_row
+                && i > max_row {
+                break;
+            }
+            for (j, shelf) in row.iter().enumerate() {
+                let oversized_count_store = oversized_count;
+                for (k, zone) in shelf.iter().enumerate() {
+                    match zone {
+                        WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {}
+                        WarehouseEntry::None => {
+                            if oversized_count == 0 {
+                                return Some((i, j, k - oversized_count_store).into())
+                            }
+                            oversized_count -= 1;
+                        }
+                    }
+                }
+                if oversized_count_store != oversized_count {
+                    oversized_count = oversized_count_store;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorClosestFirstEfficient {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let (len, constraints) = product_run(product);
+        warehouse.free_map().find_run(len, &constraints)
+    }
+}
+
+/// Places each product at the start of the smallest free run that fits it, keeping long runs for Oversized products
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorBestFit;
+
+/// Places each product at the start of the largest free run, so the space left next to it stays usable
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorWorstFit;
+
+impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorBestFit {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let (len, constraints) = product_run(product);
+        // min_by_key keeps the first of equal runs, so ties go to the closest one
+        warehouse.free_map().runs(&constraints)
+            .filter(|(_, run)| *run >= len)
+            .min_by_key(|(_, run)| *run)
+            .map(|(start, _)| start)
+    }
+}
+
+impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorWorstFit {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let (len, constraints) = product_run(product);
+        // max_by_key would keep the last of equal runs, ties should go to the closest one
+        warehouse.free_map().runs(&constraints)
+            .filter(|(_, run)| *run >= len)
+            .min_by_key(|(_, run)| std::cmp::Reverse(*run))
+            .map(|(start, _)| start)
+    }
+}
+
+/// Keeps stacks of a product together, on the shelf of an existing stack if possible, else on its row
+/// Products without stacks, or without room near them, are left to the fallback allocator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorAffinity<A> {
+    fallback: A,
+}
+
+impl<A> WarehouseAllocatorAffinity<A> {
+    pub fn new(fallback: A) -> Self {
+        WarehouseAllocatorAffinity { fallback }
+    }
+}
+
+impl<I: Product, A: WarehouseAllocator<I>> WarehouseAllocator<I> for WarehouseAllocatorAffinity<A> {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let (len, constraints) = product_run(product);
+        let stacks = warehouse.search_by_id(product.identifier()).into_iter().flatten()
+            .chain(warehouse.search_by_name(product.name()).into_iter().flatten())
+            .filter(|stack| constraints.rows.as_ref().is_none_or(|rows| rows.contains(&stack.0)));
+
+        // Runs on the same shelf are 0 shelves away, so they always win over the rest of the row
+        let mut nearest: Option<((usize, usize), StoreCoords)> = None;
+        for stack in stacks {
+            let runs = warehouse.free_map().runs(&RunConstraints::rows(stack.0..=stack.0));
+            for (start, run) in runs.filter(|(_, run)| *run >= len) {
+                // A run ending before the stack on its shelf is filled from its end, right next to it
+                let zone = if start.1 == stack.1 && start.2 < stack.2 { start.2 + run - len } else { start.2 };
+                let distance = (start.1.abs_diff(stack.1), zone.abs_diff(stack.2));
+                if nearest.as_ref().is_none_or(|(best, _)| distance < *best) {
+                    nearest = Some((distance, StoreCoords(start.0, start.1, zone)));
+                }
+            }
+        }
+        nearest.map(|(_, start)| start).or_else(|| self.fallback.next(warehouse, product))
+    }
+}
+
+/// Puts Fragile products expiring within soon of the reference date in the first free zones, on low rows and front shelves,
+/// and longer lasting ones as deep as their max_row allows. Other products are left to the fallback allocator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorExpiry<A> {
+    // Current day when None
+    as_of: Option<Date>,
+    #[serde(with = "days")]
+    soon: Duration,
+    fallback: A,
+}
+
+impl<A> WarehouseAllocatorExpiry<A> {
+    pub fn new(as_of: Option<Date>, soon: Duration, fallback: A) -> Self {
+        WarehouseAllocatorExpiry { as_of, soon, fallback }
+    }
+}
+
+impl<I: Product, A: WarehouseAllocator<I>> WarehouseAllocator<I> for WarehouseAllocatorExpiry<A> {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let ProductCategory::Fragile { expiry_date, .. } = product.quality() else {
+            return self.fallback.next(warehouse, product);
+        };
+        let (len, constraints) = product_run(product);
+        let as_of = self.as_of.unwrap_or_else(|| UtcDateTime::now().date());
+        // Past the last representable date everything expires soon
+        let expires_soon = as_of.checked_add(self.soon).is_none_or(|limit| *expiry_date <= limit);
+
+        let spot = if expires_soon {
+            warehouse.free_map().find_run(len, &constraints)
+        } else {
+            warehouse.free_map().find_last_run(len, &constraints)
+        };
+        spot.or_else(|| self.fallback.next(warehouse, product))
+    }
+}
+
+/// Puts A class products in the first free zones, the closest to the dock, and C class ones as deep as they may go
+/// B class products, and products never stored or picked before, are left to the fallback allocator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorVelocity<A> {
+    thresholds: AbcThresholds,
+    fallback: A,
+}
+
+impl<A> WarehouseAllocatorVelocity<A> {
+    pub fn new(thresholds: AbcThresholds, fallback: A) -> Self {
+        WarehouseAllocatorVelocity { thresholds, fallback }
+    }
+}
+
+impl<I: Product, A: WarehouseAllocator<I>> WarehouseAllocator<I> for WarehouseAllocatorVelocity<A> {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let (len, constraints) = product_run(product);
+        let spot = match warehouse.velocity_classes(&self.thresholds).get(product.identifier()) {
+            Some(VelocityClass::A) => warehouse.free_map().find_run(len, &constraints),
+            Some(VelocityClass::C) => warehouse.free_map().find_last_run(len, &constraints),
+            Some(VelocityClass::B) | None => None,
+        };
+        spot.or_else(|| self.fallback.next(warehouse, product))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorRoundRobin {
+    last_coords: StoreCoords,
+}
+
+impl WarehouseAllocatorRoundRobin {
+    pub fn new() -> Self {
+        WarehouseAllocatorRoundRobin {
+            last_coords: (0,0,0).into()
+        }
+    }
+}
+
+impl Default for WarehouseAllocatorRoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorRoundRobin {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let mut oversized_count = if let ProductCategory::Oversized { zone_count } = product.quality() {
+            *zone_count
+        } else { 0 };
+        let max_row = if let ProductCategory::Fragile { max_row, .. } = product.quality() {
+            Some(*max_row)
+        } else { None };
+        let (mut i, mut j, mut k) = (&self.last_coords).into();
+        
+        while i < warehouse.store().len() {
+            if let Some(max_row) = max_row
+                && i > max_row {
+                break;
+            }
+            let row = &warehouse.store()[i];
+            while j < row.len() {
+                let shelf = &row[j];
+                let oversized_count_store = oversized_count;
+                while k < shelf.len() {
+                    let zone = &shelf[k];
+                    
+                    match zone {
+                        WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {}
+                        WarehouseEntry::None => {
+                            if oversized_count == 0 {
+                                let coords: StoreCoords = (i, j, k).into();
+                                self.last_coords = coords.clone();
+                                return Some(coords);
+                            }
+                            oversized_count -= 1;
+                        }
+                    }
+                    
+                    k += 1;
+                }
+                
+                j += 1;
+                k = 0;
+                if oversized_count_store != oversized_count {
+                    oversized_count = oversized_count_store;
+                }
+            }
+            
+            i += 1;
+            j = 0;
+        }
+
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarehouseAllocatorRoundRobinEfficient {
+    last_coords: StoreCoords,
+}
+
+impl WarehouseAllocatorRoundRobinEfficient {
+    pub fn new() -> Self {
+        WarehouseAllocatorRoundRobinEfficient {
+            last_coords: (0,0,0).into()
+        }
+    }
+}
+
+impl Default for WarehouseAllocatorRoundRobinEfficient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorRoundRobinEfficient {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        let next_coords = self.last_coords.next(&warehouse.dimensions())?;
+        let iter = warehouse.free_map().iter_from(next_coords);
+        for range in iter {
+            // We don't care about partial overlaps here, since last_coords should always be pointing at the last allocation
+            if let ProductCategory::Fragile { max_row, .. } = product.quality()
+                && range.start().0 > *max_row {
+                continue
+            }
+
+            if let ProductCategory::Oversized { zone_count } = product.quality() {
+                let mut try_end = range.start().clone();
+                if try_end.2 + zone_count >= warehouse.dimensions().zones {
+                    return None
+                }
+                try_end.2 += zone_count;
+                if !range.contains(&try_end) {
+                    return None
+                }
+            }
+
+            self.last_coords = range.start().clone();
+            return Some(self.last_coords.clone())
+        }
+
+        None
+    }
+}
+
+// Lets composed allocators hold any other allocator as their fallback
+impl<I: Product, A: WarehouseAllocator<I> + ?Sized> WarehouseAllocator<I> for Box<A> {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        (**self).next(warehouse, product)
+    }
+}
+
+/// Any of the built-in allocators with its state, so the one in use can be chosen at runtime and saved with the warehouse
+/// Config files hold one as JSON, as in "best-fit" or {"affinity": {"fallback": "round-robin-efficient"}}
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnyAllocator {
+    ClosestFirst,
+    #[default]
+    ClosestFirstEfficient,
+    RoundRobin(WarehouseAllocatorRoundRobin),
+    RoundRobinEfficient(WarehouseAllocatorRoundRobinEfficient),
+    BestFit,
+    WorstFit,
+    Affinity(WarehouseAllocatorAffinity<Box<AnyAllocator>>),
+    Expiry(WarehouseAllocatorExpiry<Box<AnyAllocator>>),
+    Velocity(WarehouseAllocatorVelocity<Box<AnyAllocator>>),
+}
+
+impl AnyAllocator {
+    /// Name the allocator is registered under
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnyAllocator::ClosestFirst => "closest-first",
+            AnyAllocator::ClosestFirstEfficient => "closest-first-efficient",
+            AnyAllocator::RoundRobin(_) => "round-robin",
+            AnyAllocator::RoundRobinEfficient(_) => "round-robin-efficient",
+            AnyAllocator::BestFit => "best-fit",
+            AnyAllocator::WorstFit => "worst-fit",
+            AnyAllocator::Affinity(_) => "affinity",
+            AnyAllocator::Expiry(_) => "expiry",
+            AnyAllocator::Velocity(_) => "velocity",
+        }
+    }
+
+    fn fallback(&self) -> Option<&AnyAllocator> {
+        match self {
+            AnyAllocator::Affinity(allocator) => Some(&allocator.fallback),
+            AnyAllocator::Expiry(allocator) => Some(&allocator.fallback),
+            AnyAllocator::Velocity(allocator) => Some(&allocator.fallback),
+            _ => None,
+        }
+    }
+
+    // Config files hold a single allocator
+    pub fn load_config(reader: &mut impl BufRead) -> Result<AnyAllocator, serde_json::Error> {
+        serde_json::from_reader(reader)
+    }
+}
+
+// Composed allocators are followed by their fallback, as in "affinity, then closest-first-efficient"
+impl Display for AnyAllocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())?;
+        match self.fallback() {
+            Some(fallback) => write!(f, ", then {}", fallback),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<I: Product> WarehouseAllocator<I> for AnyAllocator {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
+        match self {
+            AnyAllocator::ClosestFirst => WarehouseAllocatorClosestFirst.next(warehouse, product),
+            AnyAllocator::ClosestFirstEfficient => WarehouseAllocatorClosestFirstEfficient.next(warehouse, product),
+            AnyAllocator::RoundRobin(allocator) => allocator.next(warehouse, product),
+            AnyAllocator::RoundRobinEfficient(allocator) => allocator.next(warehouse, product),
+            AnyAllocator::BestFit => WarehouseAllocatorBestFit.next(warehouse, product),
+            AnyAllocator::WorstFit => WarehouseAllocatorWorstFit.next(warehouse, product),
+            AnyAllocator::Affinity(allocator) => allocator.next(warehouse, product),
+            AnyAllocator::Expiry(allocator) => allocator.next(warehouse, product),
+            AnyAllocator::Velocity(allocator) => allocator.next(warehouse, product),
+        }
+    }
+}
+
+pub struct RegisteredAllocator {
+    pub name: &'static str,
+    pub description: &'static str,
+    build: fn() -> AnyAllocator,
+}
+
+impl RegisteredAllocator {
+    /// A new allocator with its default settings, composed ones falling back to closest-first-efficient
+    pub fn build(&self) -> AnyAllocator {
+        (self.build)()
+    }
+}
+
+fn default_fallback() -> Box<AnyAllocator> {
+    Box::new(AnyAllocator::default())
+}
+
+/// Every built-in allocator, by the name used on the command line and in the menu
+pub const REGISTRY: [RegisteredAllocator; 9] = [
+    RegisteredAllocator {
+        name: "closest-first",
+        description: "First free zones from the start of the warehouse, scanning the whole store",
+        build: || AnyAllocator::ClosestFirst,
+    },
+    RegisteredAllocator {
+        name: "closest-first-efficient",
+        description: "First free zones from the start of the warehouse, using the free map",
+        build: || AnyAllocator::ClosestFirstEfficient,
+    },
+    RegisteredAllocator {
+        name: "round-robin",
+        description: "First free zones after the last product placed, scanning the whole store",
+        build: || AnyAllocator::RoundRobin(WarehouseAllocatorRoundRobin::new()),
+    },
+    RegisteredAllocator {
+        name: "round-robin-efficient",
+        description: "First free zones after the last product placed, using the free map",
+        build: || AnyAllocator::RoundRobinEfficient(WarehouseAllocatorRoundRobinEfficient::new()),
+    },
+    RegisteredAllocator {
+        name: "best-fit",
+        description: "Smallest free run that fits the product",
+        build: || AnyAllocator::BestFit,
+    },
+    RegisteredAllocator {
+        name: "worst-fit",
+        description: "Largest free run",
+        build: || AnyAllocator::WorstFit,
+    },
+    RegisteredAllocator {
+        name: "affinity",
+        description: "Next to other stacks of the same product, on their shelf or row",
+        build: || AnyAllocator::Affinity(WarehouseAllocatorAffinity::new(default_fallback())),
+    },
+    RegisteredAllocator {
+        name: "expiry",
+        description: "Fragile products expiring within a week at the front, longer lasting ones at the back",
+        build: || AnyAllocator::Expiry(WarehouseAllocatorExpiry::new(None, Duration::days(7), default_fallback())),
+    },
+    RegisteredAllocator {
+        name: "velocity",
+        description: "Most picked products at the front, least picked ones at the back",
+        build: || AnyAllocator::Velocity(WarehouseAllocatorVelocity::new(AbcThresholds::default(), default_fallback())),
+    },
+];
+
+pub fn by_name(name: &str) -> Option<AnyAllocator> {
+    REGISTRY.iter().find(|entry| entry.name == name).map(RegisteredAllocator::build)
+}
+
+// Durations are written as whole days, which is what config files use them for
+mod days {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(duration.whole_days())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let days = i64::deserialize(deserializer)?;
+        days.checked_mul(86_400).map(Duration::seconds).ok_or_else(|| D::Error::custom(format!("{} days is too long", days)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AnyOldProduct;
+    use crate::coords::WarehouseDimensions;
+    use crate::warehouse::{ModificationError, WarehouseChange};
+
+    fn stack(identifier: i64) -> AnyOldProduct {
+        AnyOldProduct::new(identifier, format!("Item {}", identifier), 10, ProductCategory::Normal)
+    }
+
+    // Puts a product in place without going through an allocator
+    fn place(warehouse: &mut Warehouse<AnyOldProduct>, product: AnyOldProduct, coords: StoreCoords) {
+        warehouse.apply_change(&WarehouseChange::Added { coords, product, positions: None }).unwrap();
+    }
+
+    #[test]
+    fn test_allocator_skips_short_ranges() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        let bolts = AnyOldProduct::new(1, "Bolts".to_string(), 1, ProductCategory::Normal);
+        warehouse.add_product(bolts, &mut allocator).unwrap();
+
+        // Zones 0,0,1 to 0,0,3 cannot hold four zones, the beam goes on the next shelf
+        let beam = AnyOldProduct::new(2, "Beam".to_string(), 1, ProductCategory::Oversized { zone_count: 3 });
+        warehouse.add_product(beam, &mut allocator).unwrap();
+        assert_eq!(warehouse.search_by_id(&2), Some(&vec![StoreCoords(0, 1, 0)]));
+
+        // Row 0 is full once the first shelf is, so a fragile product that must stay on it is refused
+        for identifier in 3..6 {
+            let nuts = AnyOldProduct::new(identifier, "Nuts".to_string(), 1, ProductCategory::Normal);
+            warehouse.add_product(nuts, &mut allocator).unwrap();
+        }
+        let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        let milk = AnyOldProduct::new(6, "Milk".to_string(), 1, ProductCategory::Fragile { expiry_date, max_row: 0 });
+        assert!(matches!(warehouse.add_product(milk, &mut allocator), Err(ModificationError::Full)));
+        let milk = AnyOldProduct::new(7, "Milk".to_string(), 1, ProductCategory::Fragile { expiry_date, max_row: 1 });
+        warehouse.add_product(milk, &mut allocator).unwrap();
+        assert_eq!(warehouse.search_by_id(&7), Some(&vec![StoreCoords(1, 0, 0)]));
+    }
+
+    // Shelves of 0,0 and 0,1 left with two and one free zones at the end, shelf 0,2 empty
+    fn fragmented_warehouse() -> Warehouse<AnyOldProduct> {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 3, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for identifier in 0..8 {
+            let bolts = AnyOldProduct::new(identifier, "Bolts".to_string(), 1, ProductCategory::Normal);
+            warehouse.add_product(bolts, &mut allocator).unwrap();
+        }
+        for coords in [StoreCoords(0, 0, 2), StoreCoords(0, 0, 3), StoreCoords(0, 1, 3)] {
+            warehouse.remove_product(coords).unwrap();
+        }
+        warehouse
+    }
+
+    // Adds a one, three and two zone product, returning how many were refused and the free runs left
+    fn mixed_workload(allocator: &mut impl WarehouseAllocator<AnyOldProduct>) -> (usize, Vec<(StoreCoords, usize)>) {
+        let mut warehouse = fragmented_warehouse();
+        let products = [
+            AnyOldProduct::new(10, "Nuts".to_string(), 1, ProductCategory::Normal),
+            AnyOldProduct::new(11, "Beam".to_string(), 1, ProductCategory::Oversized { zone_count: 2 }),
+            AnyOldProduct::new(12, "Pipe".to_string(), 1, ProductCategory::Oversized { zone_count: 1 }),
+        ];
+        let refused = products.into_iter().filter(|product| warehouse.add_product(product.clone(), allocator).is_err()).count();
+        (refused, warehouse.free_map().runs(&RunConstraints::default()).collect())
+    }
+
+    #[test]
+    fn test_fit_allocators_fragment_less() {
+        // The nuts take one of the two zones meant for the pipe, which no longer fits anywhere
+        let (refused, runs) = mixed_workload(&mut WarehouseAllocatorClosestFirstEfficient);
+        assert_eq!(refused, 1);
+        assert_eq!(runs, vec![(StoreCoords(0, 0, 3), 1), (StoreCoords(0, 1, 3), 1), (StoreCoords(0, 2, 3), 1)]);
+
+        // The nuts fill the single free zone exactly
+        let (refused, runs) = mixed_workload(&mut WarehouseAllocatorBestFit);
+        assert_eq!(refused, 0);
+        assert_eq!(runs, vec![(StoreCoords(0, 2, 3), 1)]);
+
+        // The nuts go on the empty shelf, which still has room for the beam
+        let (refused, runs) = mixed_workload(&mut WarehouseAllocatorWorstFit);
+        assert_eq!(refused, 0);
+        assert_eq!(runs, vec![(StoreCoords(0, 1, 3), 1)]);
+    }
+
+    #[test]
+    fn test_fit_allocators_respect_max_row() {
+        let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        let milk = AnyOldProduct::new(1, "Milk".to_string(), 1, ProductCategory::Fragile { expiry_date, max_row: 0 });
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 1, 4));
+        let mut closest = WarehouseAllocatorClosestFirstEfficient;
+        for identifier in 2..5 {
+            let bolts = AnyOldProduct::new(identifier, "Bolts".to_string(), 1, ProductCategory::Normal);
+            warehouse.add_product(bolts, &mut closest).unwrap();
+        }
+        // The smallest and the largest free runs are both out of reach on row 1
+        let bolts = AnyOldProduct::new(5, "Bolts".to_string(), 1, ProductCategory::Normal);
+        place(&mut warehouse, bolts, StoreCoords(1, 0, 1));
+
+        assert_eq!(WarehouseAllocatorBestFit.next(&warehouse, &milk), Some(StoreCoords(0, 0, 3)));
+        assert_eq!(WarehouseAllocatorWorstFit.next(&warehouse, &milk), Some(StoreCoords(0, 0, 3)));
+        warehouse.add_product(milk.clone(), &mut WarehouseAllocatorBestFit).unwrap();
+        assert_eq!(WarehouseAllocatorBestFit.next(&warehouse, &milk), None);
+        assert_eq!(WarehouseAllocatorWorstFit.next(&warehouse, &milk), None);
+    }
+
+    #[test]
+    fn test_affinity_allocator() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 3, 4));
+        let mut allocator = WarehouseAllocatorAffinity::new(WarehouseAllocatorClosestFirstEfficient);
+        let nuts = |identifier| AnyOldProduct::new(identifier, "Nuts".to_string(), 1, ProductCategory::Normal);
+        place(&mut warehouse, nuts(1), StoreCoords(0, 1, 2));
+
+        // Next to the stack first, filling its shelf, then the closest shelves of its row
+        // A different identifier with the same name is kept with it too
+        for (identifier, coords) in [(1, StoreCoords(0, 1, 1)), (2, StoreCoords(0, 1, 3)), (1, StoreCoords(0, 1, 0)), (1, StoreCoords(0, 0, 0))] {
+            warehouse.add_product(nuts(identifier), &mut allocator).unwrap();
+            assert!(warehouse.search_by_id(&identifier).unwrap().contains(&coords));
+        }
+
+        // Unknown products go to the fallback
+        let bolts = AnyOldProduct::new(3, "Bolts".to_string(), 1, ProductCategory::Normal);
+        warehouse.add_product(bolts, &mut allocator).unwrap();
+        assert_eq!(warehouse.search_by_id(&3), Some(&vec![StoreCoords(0, 0, 1)]));
+
+        // Stacks on rows a Fragile product may not use are ignored
+        let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        let milk = |max_row| AnyOldProduct::new(5, "Milk".to_string(), 1, ProductCategory::Fragile { expiry_date, max_row });
+        place(&mut warehouse, milk(1), StoreCoords(1, 0, 3));
+        assert_eq!(allocator.next(&warehouse, &milk(1)), Some(StoreCoords(1, 0, 2)));
+        assert_eq!(allocator.next(&warehouse, &milk(0)), Some(StoreCoords(0, 0, 2)));
+
+        // Once no shelf of the row has room for a beam, it goes to the fallback as well
+        let beam = || AnyOldProduct::new(4, "Beam".to_string(), 1, ProductCategory::Oversized { zone_count: 3 });
+        place(&mut warehouse, beam(), StoreCoords(1, 2, 0));
+        warehouse.add_product(beam(), &mut allocator).unwrap();
+        assert!(warehouse.search_by_id(&4).unwrap().contains(&StoreCoords(1, 1, 0)));
+        warehouse.add_product(beam(), &mut allocator).unwrap();
+        assert!(warehouse.search_by_id(&4).unwrap().contains(&StoreCoords(0, 2, 0)));
+    }
+
+    #[test]
+    fn test_expiry_allocator() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(3, 2, 4));
+        let as_of = time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        let mut allocator = WarehouseAllocatorExpiry::new(Some(as_of), time::Duration::days(7), WarehouseAllocatorClosestFirstEfficient);
+        let fragile = |identifier, day, max_row| {
+            let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, day).unwrap();
+            AnyOldProduct::new(identifier, "Milk".to_string(), 1, ProductCategory::Fragile { expiry_date, max_row })
+        };
+
+        // Expiring within the week, including on its last day, to the front
+        for (product, coords) in [(fragile(1, 5, 2), StoreCoords(0, 0, 0)), (fragile(2, 8, 2), StoreCoords(0, 0, 1))] {
+            assert_eq!(allocator.next(&warehouse, &product), Some(coords.clone()));
+            place(&mut warehouse, product, coords);
+        }
+        // Longer lasting ones to the back, as far as max_row allows
+        assert_eq!(allocator.next(&warehouse, &fragile(3, 9, 2)), Some(StoreCoords(2, 1, 3)));
+        assert_eq!(allocator.next(&warehouse, &fragile(3, 30, 1)), Some(StoreCoords(1, 1, 3)));
+
+        let beam = AnyOldProduct::new(4, "Beam".to_string(), 1, ProductCategory::Oversized { zone_count: 1 });
+        assert_eq!(allocator.next(&warehouse, &beam), Some(StoreCoords(0, 0, 2)));
+    }
+
+    #[test]
+    fn test_velocity_allocator() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 3, 3));
+        let stack = |identifier| AnyOldProduct::new(identifier, format!("Item {}", identifier), 10, ProductCategory::Normal);
+        for (identifier, coords) in [(3, StoreCoords(0, 0, 0)), (3, StoreCoords(0, 0, 1)), (2, StoreCoords(0, 1, 0)), (1, StoreCoords(0, 2, 0))] {
+            place(&mut warehouse, stack(identifier), coords);
+        }
+        for _ in 0..8 {
+            warehouse.pick(&StoreCoords(0, 2, 0), 1).unwrap();
+        }
+        warehouse.pick(&StoreCoords(0, 1, 0), 1).unwrap();
+
+        // Worst-fit goes for the longest run, on shelf 0,1, so the fallback is easy to tell apart
+        let mut allocator = WarehouseAllocatorVelocity::new(AbcThresholds::default(), WarehouseAllocatorWorstFit);
+        assert_eq!(allocator.next(&warehouse, &stack(1)), Some(StoreCoords(0, 0, 2)));
+        assert_eq!(allocator.next(&warehouse, &stack(3)), Some(StoreCoords(0, 2, 2)));
+        assert_eq!(allocator.next(&warehouse, &stack(2)), Some(StoreCoords(0, 1, 1)));
+        assert_eq!(allocator.next(&warehouse, &stack(9)), Some(StoreCoords(0, 1, 1)));
+    }
+
+    #[test]
+    fn test_registry() {
+        for entry in &REGISTRY {
+            assert_eq!(entry.build().name(), entry.name);
+        }
+        assert_eq!(by_name("best-fit"), Some(AnyAllocator::BestFit));
+        assert_eq!(by_name("first-fit"), None);
+        assert_eq!(by_name("affinity").unwrap().to_string(), "affinity, then closest-first-efficient");
+    }
+
+    #[test]
+    fn test_load_config() {
+        let config = r#"{"expiry": {"as_of": null, "soon": 3, "fallback": {"affinity": {"fallback": "best-fit"}}}}"#;
+        let allocator = AnyAllocator::load_config(&mut config.as_bytes()).unwrap();
+        let expected = AnyAllocator::Expiry(WarehouseAllocatorExpiry::new(
+            None,
+            Duration::days(3),
+            Box::new(AnyAllocator::Affinity(WarehouseAllocatorAffinity::new(Box::new(AnyAllocator::BestFit)))),
+        ));
+        assert_eq!(allocator, expected);
+        assert_eq!(allocator.to_string(), "expiry, then affinity, then best-fit");
+        assert!(AnyAllocator::load_config(&mut r#""first-fit""#.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_round_robin_state_saved_in_snapshots() {
+        for name in ["round-robin", "round-robin-efficient"] {
+            let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 2, 4));
+            let mut allocator = by_name(name).unwrap();
+            for identifier in 1..=3 {
+                warehouse.add_product(stack(identifier), &mut allocator).unwrap();
+            }
+            warehouse.remove_product(StoreCoords(0, 0, 1)).unwrap();
+            warehouse.set_allocator(Some(allocator.clone()));
+            // A new allocator would take the freed zone
+            assert_eq!(by_name(name).unwrap().next(&warehouse, &stack(4)), Some(StoreCoords(0, 0, 1)));
+
+            let mut json = Vec::new();
+            warehouse.to_json(&mut json).unwrap();
+            let (from_json, _) = Warehouse::<AnyOldProduct>::from_json(&mut json.as_slice()).unwrap();
+            let mut binary = Vec::new();
+            warehouse.to_binary(&mut binary, true).unwrap();
+            let from_binary = Warehouse::<AnyOldProduct>::from_binary(&mut binary.as_slice()).unwrap();
+
+            for mut loaded in [from_json, from_binary] {
+                assert_eq!(loaded.allocator(), Some(&allocator));
+                // The saved one carries on after the last stack it placed
+                let mut allocator = loaded.allocator().cloned().unwrap();
+                loaded.add_product(stack(4), &mut allocator).unwrap();
+                assert_eq!(loaded.search_by_id(&4), Some(&vec![StoreCoords(0, 0, 3)]));
+            }
+        }
+    }
+}
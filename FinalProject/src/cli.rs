@@ -0,0 +1,634 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use serde_json::json;
+use thiserror::Error;
+use crate::coords::StoreCoords;
+use crate::history::History;
+use crate::csv::format_timestamp;
+use crate::script::{run_script, SCRIPT_HELP};
+use crate::warehouse::{AbcThresholds, ModificationError, PickMode, PickTarget, Product, ProductCategory, SnapshotError, SweepAction, Warehouse, WarehouseEntry};
+use crate::allocators::{self, AnyAllocator};
+use crate::AnyOldProduct;
+
+const USAGE: &str = "\
+Usage: warehouse <command> --store <path.json> [--json] [options]
+
+Commands:
+  add       --id <n> --name <text> --amount <n> [--category normal|fragile|oversized]
+            [--expiry YYYY-MM-DD --max-row <n>] [--zones <n>] [--allocator <name> | --allocator-config <path>]
+  remove    --at <row,shelf,zone>
+  search    --name <text> | --id <n>
+  expiring  --before YYYY-MM-DD
+  pick      --product <id or name> --quantity <n> [--order fefo|fifo] [--dry-run]
+            Takes the quantity from the stacks expiring soonest, or stored the longest with fifo,
+            printing where each part comes from
+  oldest    --min-age-days <n>  Lists the stacks stored for at least that many days, oldest first
+  reslot    [--classes <a,b>]
+            Lists the moves bringing the fastest moving products to the front, after classing them by their
+            share of all picks, 80,95 by default for the A and then the A and B classes
+  sweep     [--as-of YYYY-MM-DD] [--quarantine <row,shelf,zone>..<row,shelf,zone>]
+            Removes Fragile stacks that expired before the date, today by default, or moves them into the area
+  export    --to <path>    Writes the store to another file, or stdout with -
+  import    --from <path>  Replaces the store with a snapshot, after checking it
+  script    [--file <path>] [--stop-on-error] [--allocator <name> | --allocator-config <path>]
+            Runs a command file, or stdin without --file, saving the store afterwards if one is given.
+            Running the binary with piped stdin and no arguments does the same without a store
+
+The store file is created on the first add if it does not exist.
+Files ending in .bin, as store or for export and import, use the compressed binary snapshot instead of JSON.
+Coordinates start at 0.
+The allocator placing new stacks is saved with the store, closest-first-efficient until one is chosen. Names:
+  closest-first, closest-first-efficient, round-robin, round-robin-efficient, best-fit, worst-fit,
+  affinity, expiry, velocity
+A config file holds one allocator as JSON, such as {\"affinity\": {\"fallback\": \"best-fit\"}}.
+
+Exit codes: 0 success, 1 operation refused, 2 bad usage, 3 store could not be read or written, 4 nothing found
+
+Script commands, one per line:";
+
+const COMMANDS: [&str; 11] = ["add", "remove", "search", "expiring", "pick", "oldest", "reslot", "sweep", "export", "import", "script"];
+
+// Options that do not take a value
+const FLAGS: [&str; 4] = ["json", "help", "stop-on-error", "dry-run"];
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("{0}\n\n{USAGE}")]
+    Usage(String),
+    #[error(transparent)]
+    Refused(#[from] ModificationError),
+    #[error("{path}: {source}")]
+    Store {
+        path: PathBuf,
+        #[source]
+        source: SnapshotError,
+    },
+    #[error("Nothing found")]
+    NotFound,
+    #[error("{failed} script commands failed")]
+    ScriptFailed {
+        failed: usize,
+    },
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Refused(_) | CliError::ScriptFailed { .. } => 1,
+            CliError::Usage(_) => 2,
+            CliError::Store { .. } => 3,
+            CliError::NotFound => 4,
+        }
+    }
+}
+
+struct Args {
+    command: String,
+    options: BTreeMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, CliError> {
+        let mut args = args.iter();
+        let command = args.next().ok_or_else(|| CliError::Usage("No command given".to_string()))?.clone();
+        let mut options = BTreeMap::new();
+
+        while let Some(arg) = args.next() {
+            let name = arg.strip_prefix("--").ok_or_else(|| CliError::Usage(format!("Unexpected argument {}", arg)))?;
+            let value = if FLAGS.contains(&name) {
+                String::new()
+            } else {
+                args.next().ok_or_else(|| CliError::Usage(format!("Option --{} needs a value", name)))?.clone()
+            };
+            if options.insert(name.to_string(), value).is_some() {
+                return Err(CliError::Usage(format!("Option --{} given more than once", name)));
+            }
+        }
+
+        Ok(Args { command, options })
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn optional(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> Result<&str, CliError> {
+        self.optional(name).ok_or_else(|| CliError::Usage(format!("Missing option --{}", name)))
+    }
+
+    fn parsed<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, CliError> {
+        self.optional(name)
+            .map(|value| value.parse().map_err(|_| CliError::Usage(format!("Invalid value for --{}: {}", name, value))))
+            .transpose()
+    }
+
+    fn parsed_required<T: std::str::FromStr>(&self, name: &str) -> Result<T, CliError> {
+        self.parsed(name)?.ok_or_else(|| CliError::Usage(format!("Missing option --{}", name)))
+    }
+
+    fn date(&self, name: &str) -> Result<Option<time::Date>, CliError> {
+        self.optional(name)
+            .map(|value| crate::maplidator_date(value.to_string()).map_err(|e| CliError::Usage(format!("Invalid value for --{}: {}", name, e))))
+            .transpose()
+    }
+
+    // Every option given must be known to the command, so typos are not silently ignored
+    fn allow_only(&self, allowed: &[&str]) -> Result<(), CliError> {
+        match self.options.keys().find(|k| !allowed.contains(&k.as_str()) && !FLAGS.contains(&k.as_str())) {
+            Some(unknown) => Err(CliError::Usage(format!("Unknown option --{} for {}", unknown, self.command))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Entry point when the binary is called with arguments
+pub fn run(args: &[String]) -> ExitCode {
+    let mut stdout = std::io::stdout().lock();
+    match execute(args, &mut stdout) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+pub fn execute(args: &[String], out: &mut impl Write) -> Result<(), CliError> {
+    let args = Args::parse(args)?;
+    if args.flag("help") || args.command == "help" {
+        let _ = writeln!(out, "{}\n{}", USAGE, SCRIPT_HELP);
+        return Ok(());
+    }
+
+    if !COMMANDS.contains(&args.command.as_str()) {
+        return Err(CliError::Usage(format!("Unknown command {}", args.command)));
+    }
+
+    if args.command == "script" {
+        return run_script_command(&args, out);
+    }
+
+    let store = PathBuf::from(args.required("store")?);
+    let json = args.flag("json");
+
+    let (value, text) = match args.command.as_str() {
+        "add" => {
+            args.allow_only(&["store", "id", "name", "amount", "category", "expiry", "max-row", "zones", "allocator", "allocator-config"])?;
+            let product = product_from_args(&args)?;
+            let identifier = *product.identifier();
+            let mut warehouse = load_or_create(&store)?;
+            let mut allocator = allocator_from_args(&args, &warehouse)?;
+            warehouse.add_product(product, &mut allocator)?;
+            warehouse.set_allocator(Some(allocator));
+            save(&warehouse, &store)?;
+
+            let coords = warehouse.search_by_id(&identifier).and_then(|c| c.last()).cloned()
+                .expect("Product was just added");
+            (
+                json!({ "added": identifier, "at": coords }),
+                format!("Product {} added at {}", identifier, format_coords(&coords)),
+            )
+        }
+        "remove" => {
+            args.allow_only(&["store", "at"])?;
+            let coords = parse_coords(args.required("at")?)?;
+            let mut warehouse = load(&store)?;
+            let product = match warehouse.get_product_ref(&coords)? {
+                WarehouseEntry::Some(product) => product.clone(),
+                _ => return Err(ModificationError::NotFound.into()),
+            };
+            warehouse.remove_product(coords.clone())?;
+            save(&warehouse, &store)?;
+
+            (
+                json!({ "removed": product, "at": coords }),
+                format!("Removed {} from {}", product.name(), format_coords(&coords)),
+            )
+        }
+        "search" => {
+            args.allow_only(&["store", "name", "id"])?;
+            let warehouse = load(&store)?;
+            let locations = match (args.optional("name"), args.parsed::<i64>("id")?) {
+                (Some(name), None) => warehouse.search_by_name(name),
+                (None, Some(identifier)) => warehouse.search_by_id(&identifier),
+                _ => return Err(CliError::Usage("search needs exactly one of --name or --id".to_string())),
+            };
+            let products = stacks_at(&warehouse, locations.map(Vec::as_slice).unwrap_or_default());
+            if products.is_empty() {
+                return Err(CliError::NotFound);
+            }
+
+            (
+                json!(products.iter().map(|(coords, product)| json!({ "at": coords, "product": product })).collect::<Vec<_>>()),
+                products.iter()
+                    .map(|(coords, product)| format!("{}: {} x{} (ID {})", format_coords(coords), product.name(), product.amount(), product.identifier()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )
+        }
+        "expiring" => {
+            args.allow_only(&["store", "before"])?;
+            let before = args.date("before")?.ok_or_else(|| CliError::Usage("Missing option --before".to_string()))?;
+            let warehouse = load(&store)?;
+
+            let mut products = Vec::new();
+            for (date, identifiers) in warehouse.search_expiry_dates(..before) {
+                let mut seen = Vec::new();
+                for identifier in identifiers {
+                    if seen.contains(identifier) {
+                        continue;
+                    }
+                    seen.push(*identifier);
+                    let locations = warehouse.search_by_id(identifier).map(Vec::as_slice).unwrap_or_default();
+                    products.extend(stacks_at(&warehouse, locations).into_iter()
+                        .filter(|(_, p)| matches!(p.quality(), ProductCategory::Fragile { expiry_date, .. } if expiry_date == date)));
+                }
+            }
+            if products.is_empty() {
+                return Err(CliError::NotFound);
+            }
+
+            (
+                json!(products.iter().map(|(coords, product)| json!({ "at": coords, "product": product })).collect::<Vec<_>>()),
+                products.iter()
+                    .map(|(coords, product)| format!("{}: {} x{} ({})", format_coords(coords), product.name(), product.amount(), product.quality()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )
+        }
+        "pick" => {
+            args.allow_only(&["store", "product", "quantity", "order"])?;
+            let target = PickTarget::parse(args.required("product")?);
+            let quantity = args.parsed_required("quantity")?;
+            let mode = if args.flag("dry-run") { PickMode::DryRun } else { PickMode::Commit };
+            let mut warehouse = load(&store)?;
+            let plan = match args.optional("order").unwrap_or("fefo") {
+                "fefo" => warehouse.pick_fefo(&target, quantity, mode)?,
+                "fifo" => warehouse.pick_fifo(&target, quantity, mode)?,
+                other => return Err(CliError::Usage(format!("Invalid value for --order: {}", other))),
+            };
+            if mode == PickMode::Commit {
+                save(&warehouse, &store)?;
+            }
+
+            let mut lines: Vec<String> = plan.iter()
+                .map(|line| format!("{}: {}", format_coords(&line.coords), line.quantity))
+                .collect();
+            lines.push(match mode {
+                PickMode::DryRun => format!("{} units of {} would be picked from {} stacks", quantity, target, plan.len()),
+                PickMode::Commit => format!("Picked {} units of {} from {} stacks", quantity, target, plan.len()),
+            });
+            (
+                json!({ "picked": mode == PickMode::Commit, "quantity": quantity, "picks": plan }),
+                lines.join("\n"),
+            )
+        }
+        "oldest" => {
+            args.allow_only(&["store", "min-age-days"])?;
+            let days: i64 = args.parsed_required("min-age-days")?;
+            let min_age = days.checked_mul(86_400).map(time::Duration::seconds)
+                .ok_or_else(|| CliError::Usage(format!("Invalid value for --min-age-days: {} days is too long", days)))?;
+            let warehouse = load(&store)?;
+            let stacks = warehouse.oldest_stock(time::UtcDateTime::now(), min_age);
+            if stacks.is_empty() {
+                return Err(CliError::NotFound);
+            }
+
+            (
+                json!(stacks.iter().map(|stack| json!({
+                    "at": stack.coords,
+                    "identifier": stack.identifier,
+                    "name": stack.name,
+                    "amount": stack.amount,
+                    "stored_since": format_timestamp(stack.stored_since),
+                    "age_days": stack.age.whole_days(),
+                })).collect::<Vec<_>>()),
+                stacks.iter()
+                    .map(|stack| format!("{}: {} x{} (ID {}), stored {} days ago", format_coords(&stack.coords), stack.name, stack.amount, stack.identifier, stack.age.whole_days()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )
+        }
+        "reslot" => {
+            args.allow_only(&["store", "classes"])?;
+            let thresholds: AbcThresholds = args.parsed("classes")?.unwrap_or_default();
+            let warehouse = load(&store)?;
+            let classes = warehouse.velocity_classes(&thresholds);
+            let plan = warehouse.reslotting_plan(&thresholds);
+
+            let mut lines: Vec<String> = plan.iter()
+                .map(|step| format!("{} (ID {}, class {}): {} -> {}", step.name, step.identifier, step.class, format_coords(&step.from), format_coords(&step.to)))
+                .collect();
+            lines.push(match plan.len() {
+                0 => "The layout already matches the classes".to_string(),
+                moves => format!("{} moves", moves),
+            });
+            (
+                json!({ "classes": classes, "moves": plan }),
+                lines.join("\n"),
+            )
+        }
+        "sweep" => {
+            args.allow_only(&["store", "as-of", "quarantine"])?;
+            let as_of = args.date("as-of")?.unwrap_or_else(|| time::UtcDateTime::now().date());
+            let action = match args.optional("quarantine") {
+                Some(area) => {
+                    let (start, end) = area.split_once("..")
+                        .ok_or_else(|| CliError::Usage(format!("Invalid quarantine area {}, expected <from>..<to>", area)))?;
+                    SweepAction::Quarantine(parse_coords(start)?..=parse_coords(end)?)
+                }
+                None => SweepAction::Remove,
+            };
+            let mut warehouse = load(&store)?;
+            let report = warehouse.sweep_expired(as_of, &action);
+            if !report.swept.is_empty() {
+                save(&warehouse, &store)?;
+            }
+
+            let mut lines: Vec<String> = report.swept.iter()
+                .map(|swept| (&swept.stack, &swept.quarantined_at))
+                .map(|(stack, quarantined_at)| match quarantined_at {
+                    Some(to) => format!("Quarantined {} x{} (ID {}, expired {}): {} -> {}", stack.name, stack.amount, stack.identifier, stack.expiry_date, format_coords(&stack.coords), format_coords(to)),
+                    None => format!("Removed {} x{} (ID {}, expired {}) from {}", stack.name, stack.amount, stack.identifier, stack.expiry_date, format_coords(&stack.coords)),
+                })
+                .collect();
+            lines.extend(report.failed.iter()
+                .map(|(stack, e)| format!("Left {} (ID {}) at {}: {}", stack.name, stack.identifier, format_coords(&stack.coords), e)));
+            lines.push(format!("{} expired stacks swept, {} units", report.swept.len(), report.total_amount()));
+            (
+                json!({
+                    "as_of": as_of.to_string(),
+                    "swept": report.swept,
+                    "failed": report.failed.iter().map(|(stack, e)| json!({ "stack": stack, "reason": e.to_string() })).collect::<Vec<_>>(),
+                }),
+                lines.join("\n"),
+            )
+        }
+        "export" => {
+            args.allow_only(&["store", "to"])?;
+            let to = args.required("to")?;
+            let warehouse = load(&store)?;
+            if to == "-" {
+                // The snapshot is the output, so nothing else is printed
+                let result = warehouse.to_json(out).and_then(|()| writeln!(out).map_err(SnapshotError::from));
+                return result.map_err(|source| CliError::Store { path: PathBuf::from(to), source });
+            }
+            save(&warehouse, Path::new(to))?;
+
+            (
+                json!({ "exported": to }),
+                format!("Store exported to {}", to),
+            )
+        }
+        "import" => {
+            args.allow_only(&["store", "from"])?;
+            let from = PathBuf::from(args.required("from")?);
+            let (warehouse, report) = read_snapshot(&from)?;
+            save(&warehouse, &store)?;
+
+            let repaired: Vec<String> = report.issues.iter().map(ToString::to_string).collect();
+            let mut lines: Vec<String> = repaired.iter().map(|issue| format!("Repaired: {}", issue)).collect();
+            lines.push(format!("Store replaced with {}", from.display()));
+            (
+                json!({ "imported": from, "repaired": repaired }),
+                lines.join("\n"),
+            )
+        }
+        _ => unreachable!(),
+    };
+
+    // Failing to print the result does not undo the change, so it is not reported as an error
+    let _ = if json { writeln!(out, "{}", value) } else { writeln!(out, "{}", text) };
+    Ok(())
+}
+
+fn run_script_command(args: &Args, out: &mut impl Write) -> Result<(), CliError> {
+    args.allow_only(&["store", "file", "allocator", "allocator-config"])?;
+    let store = args.optional("store").map(PathBuf::from);
+    let mut warehouse = match &store {
+        Some(store) => load_or_create(store)?,
+        None => crate::new_warehouse(),
+    };
+    let mut history = History::new(50);
+    let mut allocator = allocator_from_args(args, &warehouse)?;
+    let stop_on_error = args.flag("stop-on-error");
+
+    let report = match args.optional("file") {
+        Some(file) => {
+            let script = File::open(file).map_err(|e| CliError::Store { path: PathBuf::from(file), source: e.into() })?;
+            run_script(&mut warehouse, &mut history, &mut allocator, BufReader::new(script), stop_on_error, out)
+        }
+        None => run_script(&mut warehouse, &mut history, &mut allocator, std::io::stdin().lock(), stop_on_error, out),
+    };
+
+    if let Some(store) = &store {
+        warehouse.set_allocator(Some(allocator));
+        save(&warehouse, store)?;
+    }
+
+    let summary = if args.flag("json") {
+        json!({ "executed": report.executed, "failed": report.failures.len(), "stopped": report.stopped }).to_string()
+    } else {
+        format!("{} commands executed, {} failed{}", report.executed, report.failures.len(), if report.stopped { ", run stopped" } else { "" })
+    };
+    let _ = writeln!(out, "{}", summary);
+
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(CliError::ScriptFailed { failed: report.failures.len() })
+    }
+}
+
+// The allocator given on the command line, or else the one saved with the store
+fn allocator_from_args(args: &Args, warehouse: &Warehouse<AnyOldProduct>) -> Result<AnyAllocator, CliError> {
+    match (args.optional("allocator"), args.optional("allocator-config")) {
+        (Some(_), Some(_)) => Err(CliError::Usage("Give only one of --allocator or --allocator-config".to_string())),
+        (Some(name), None) => allocators::by_name(name)
+            .ok_or_else(|| CliError::Usage(format!("Unknown allocator {}", name))),
+        (None, Some(path)) => File::open(path)
+            .map_err(SnapshotError::from)
+            .and_then(|file| AnyAllocator::load_config(&mut BufReader::new(file)).map_err(SnapshotError::from))
+            .map_err(|e| CliError::Store { path: PathBuf::from(path), source: e }),
+        (None, None) => Ok(warehouse.allocator().cloned().unwrap_or_default()),
+    }
+}
+
+fn product_from_args(args: &Args) -> Result<AnyOldProduct, CliError> {
+    let identifier = args.parsed_required("id")?;
+    let name = args.required("name")?.trim().to_string();
+    if name.is_empty() {
+        return Err(CliError::Usage("Product name cannot be empty".to_string()));
+    }
+    let amount = args.parsed_required("amount")?;
+
+    let quality = match args.optional("category").unwrap_or("normal") {
+        "normal" => ProductCategory::Normal,
+        "fragile" => ProductCategory::Fragile {
+            expiry_date: args.date("expiry")?.ok_or_else(|| CliError::Usage("Fragile products need --expiry".to_string()))?,
+            max_row: args.parsed_required("max-row")?,
+        },
+        "oversized" => ProductCategory::Oversized {
+            zone_count: args.parsed_required("zones")?,
+        },
+        other => return Err(CliError::Usage(format!("Unknown category {}", other))),
+    };
+
+    Ok(AnyOldProduct::new(identifier, name, amount, quality))
+}
+
+fn parse_coords(value: &str) -> Result<StoreCoords, CliError> {
+    value.replace(',', " ").parse().map_err(|_| CliError::Usage(format!("Invalid coordinates {}, expected row,shelf,zone", value)))
+}
+
+fn format_coords(coords: &StoreCoords) -> String {
+    format!("{},{},{}", coords.0, coords.1, coords.2)
+}
+
+fn stacks_at<'a>(warehouse: &'a Warehouse<AnyOldProduct>, locations: &[StoreCoords]) -> Vec<(StoreCoords, &'a AnyOldProduct)> {
+    locations.iter()
+        .filter_map(|coords| match warehouse.get_product_ref(coords) {
+            Ok(WarehouseEntry::Some(product)) => Some((coords.clone(), product)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_snapshot(path: &Path) -> Result<(Warehouse<AnyOldProduct>, crate::warehouse::IntegrityReport), CliError> {
+    File::open(path)
+        .map_err(SnapshotError::from)
+        .and_then(|file| if is_binary(path) {
+            Warehouse::from_binary(&mut BufReader::new(file)).map(|w| (w, Default::default()))
+        } else {
+            Warehouse::from_json(&mut BufReader::new(file))
+        })
+        .map_err(|source| CliError::Store { path: path.to_path_buf(), source })
+}
+
+fn load(path: &Path) -> Result<Warehouse<AnyOldProduct>, CliError> {
+    read_snapshot(path).map(|(warehouse, _)| warehouse)
+}
+
+fn load_or_create(path: &Path) -> Result<Warehouse<AnyOldProduct>, CliError> {
+    if path.exists() {
+        load(path)
+    } else {
+        Ok(crate::new_warehouse())
+    }
+}
+
+// Stores ending in .bin use the compressed binary snapshot, anything else is JSON
+fn is_binary(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("bin"))
+}
+
+// Written next to the target first, so a failed write never leaves a half written store behind
+fn save(warehouse: &Warehouse<AnyOldProduct>, path: &Path) -> Result<(), CliError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    File::create(&temporary)
+        .map_err(SnapshotError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            if is_binary(path) {
+                warehouse.to_binary(&mut writer, true)?;
+            } else {
+                warehouse.to_json(&mut writer)?;
+            }
+            writer.flush()?;
+            std::fs::rename(&temporary, path)?;
+            Ok(())
+        })
+        .map_err(|source| CliError::Store { path: path.to_path_buf(), source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(args: &str) -> Result<String, CliError> {
+        let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+        let mut out = Vec::new();
+        execute(&args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    // Store path named after the process, so concurrent test runs do not share it, removed even when the test fails
+    struct TempStore(std::path::PathBuf);
+
+    impl TempStore {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("warehouse_cli_{}_{}.json", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_commands_share_store() {
+        let path = TempStore::new("commands");
+        let store = path.0.display();
+
+        let added = call(&format!("add --store {} --id 42 --name Bolts --amount 100 --json", store)).unwrap();
+        let added: serde_json::Value = serde_json::from_str(&added).unwrap();
+        assert_eq!(added["added"], 42);
+        assert_eq!(added["at"], json!([0, 0, 0]));
+
+        call(&format!("add --store {} --id 7 --name Yogurt --amount 5 --category fragile --expiry 2026-12-01 --max-row 2", store)).unwrap();
+        let expiring: serde_json::Value = serde_json::from_str(&call(&format!("expiring --store {} --before 2027-01-01 --json", store)).unwrap()).unwrap();
+        assert_eq!(expiring[0]["product"]["name"], "Yogurt");
+        assert!(matches!(call(&format!("expiring --store {} --before 2026-12-01", store)), Err(CliError::NotFound)));
+
+        let found = call(&format!("search --store {} --name Bolts", store)).unwrap();
+        assert_eq!(found.trim(), "0,0,0: Bolts x100 (ID 42)");
+
+        assert!(call(&format!("remove --store {} --at 0,0,0", store)).is_ok());
+        let error = call(&format!("remove --store {} --at 0,0,0", store)).unwrap_err();
+        assert_eq!(error.exit_code(), 1);
+        assert!(matches!(call(&format!("search --store {} --id 42", store)), Err(CliError::NotFound)));
+    }
+
+    #[test]
+    fn test_usage_and_store_errors() {
+        assert_eq!(call("add --id 1").unwrap_err().exit_code(), 2);
+        assert_eq!(call("add --store x.json --id 1 --name A --amount 1 --colour red").unwrap_err().exit_code(), 2);
+        assert!(matches!(call("fly"), Err(CliError::Usage(message)) if message == "Unknown command fly"));
+        assert_eq!(call("remove --store x.json --at 1,2").unwrap_err().exit_code(), 2);
+        assert_eq!(call("oldest --store x.json --min-age-days 999999999999999").unwrap_err().exit_code(), 2);
+
+        let path = TempStore::new("missing");
+        let missing = path.0.display();
+        assert_eq!(call(&format!("search --store {} --id 1", missing)).unwrap_err().exit_code(), 3);
+        assert_eq!(call(&format!("add --store {} --id 1 --name A --amount 1 --allocator first-fit", missing)).unwrap_err().exit_code(), 2);
+        assert_eq!(call(&format!("add --store {} --id 1 --name A --amount 1 --allocator-config {}", missing, missing)).unwrap_err().exit_code(), 3);
+    }
+
+    #[test]
+    fn test_store_keeps_allocator() {
+        let path = TempStore::new("allocator");
+        let store = path.0.display();
+
+        call(&format!("add --store {} --id 1 --name Bolts --amount 1 --allocator round-robin-efficient", store)).unwrap();
+        call(&format!("add --store {} --id 2 --name Nuts --amount 1", store)).unwrap();
+        call(&format!("add --store {} --id 3 --name Pins --amount 1", store)).unwrap();
+        call(&format!("remove --store {} --at 0,0,1", store)).unwrap();
+        // The saved round robin carries on after the last stack, where a new one would reuse the freed zone
+        let added: serde_json::Value = serde_json::from_str(&call(&format!("add --store {} --id 4 --name Washers --amount 1 --json", store)).unwrap()).unwrap();
+        assert_eq!(added["at"], json!([0, 0, 3]));
+    }
+}
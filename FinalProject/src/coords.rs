@@ -1,32 +1,90 @@
+use std::fmt::Display;
 use std::str::FromStr;
 use rangemap::{StepLite};
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(PartialEq, Eq, Clone, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+/// Most zones a warehouse loaded from a file may have, so a corrupt size cannot exhaust memory
+pub const MAX_ZONES: usize = 1 << 24;
+
+#[derive(PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(try_from = "DimensionsData")]
+pub struct WarehouseDimensions {
+    pub rows: usize,
+    pub shelves: usize,
+    pub zones: usize,
+}
+
+// Saved form of the dimensions, checked like new() before use
+#[derive(Deserialize)]
+struct DimensionsData {
+    rows: usize,
+    shelves: usize,
+    zones: usize,
+}
+
+impl TryFrom<DimensionsData> for WarehouseDimensions {
+    type Error = &'static str;
+
+    fn try_from(data: DimensionsData) -> Result<Self, Self::Error> {
+        if data.rows == 0 || data.shelves == 0 || data.zones == 0 {
+            return Err("Warehouse dimensions cannot be zero");
+        }
+        Ok(WarehouseDimensions::new(data.rows, data.shelves, data.zones))
+    }
+}
+
+impl WarehouseDimensions {
+    pub fn new(rows: usize, shelves: usize, zones: usize) -> Self {
+        assert!(rows > 0 && shelves > 0 && zones > 0, "Warehouse dimensions cannot be zero");
+        WarehouseDimensions { rows, shelves, zones }
+    }
+
+    pub fn contains(&self, coords: &StoreCoords) -> bool {
+        coords.0 < self.rows && coords.1 < self.shelves && coords.2 < self.zones
+    }
+
+    // Number of zones in the warehouse, None if it does not fit in a usize
+    pub fn zone_count(&self) -> Option<usize> {
+        self.rows.checked_mul(self.shelves)?.checked_mul(self.zones)
+    }
+
+    // Coordinates of the last zone in the warehouse
+    pub fn last(&self) -> StoreCoords {
+        (self.rows - 1, self.shelves - 1, self.zones - 1).into()
+    }
+}
+
+impl Display for WarehouseDimensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} rows, {} shelves, {} zones", self.rows, self.shelves, self.zones)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct StoreCoords(pub usize, pub usize, pub usize);
 
 impl StoreCoords {
-    pub fn previous(&self, store_max_idx: usize) -> Option<Self> {
+    pub fn previous(&self, dimensions: &WarehouseDimensions) -> Option<Self> {
         if self.2 != 0 {
             return Some((self.0, self.1, self.2-1).into());
         }
         if self.1 != 0 {
-            return Some((self.0, self.1 - 1, store_max_idx - 1).into());
+            return Some((self.0, self.1 - 1, dimensions.zones - 1).into());
         }
         if self.0 != 0 {
-            return Some((self.0 - 1, store_max_idx - 1, store_max_idx -1).into());
+            return Some((self.0 - 1, dimensions.shelves - 1, dimensions.zones - 1).into());
         }
         None
     }
     
-    pub fn next(&self,store_max_idx: usize) -> Option<Self> {
-        if self.2 != store_max_idx - 1 {
+    pub fn next(&self, dimensions: &WarehouseDimensions) -> Option<Self> {
+        if self.2 != dimensions.zones - 1 {
             return Some((self.0, self.1, self.2+1).into());
         }
-        if self.1 != store_max_idx - 1 {
+        if self.1 != dimensions.shelves - 1 {
             return Some((self.0, self.1+1, 0).into());
         }
-        if self.0 != store_max_idx - 1 {
+        if self.0 != dimensions.rows - 1 {
             return Some((self.0+1, 0, 0).into());
         }
         None
@@ -65,16 +123,21 @@ impl From<&StoreCoords> for (usize, usize, usize) {
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct LimitStoreCoords {
     coords: StoreCoords,
-    max_idx: usize,
+    dimensions: WarehouseDimensions,
 }
 
 impl LimitStoreCoords {
-    pub fn from_with_max(coords: StoreCoords, max_idx: usize) -> Self {
+    pub fn from_with_dimensions(coords: StoreCoords, dimensions: WarehouseDimensions) -> Self {
         LimitStoreCoords {
             coords,
-            max_idx
+            dimensions
         }
     }
+    
+    // Sentinel past the end of the warehouse, used when stepping out of bounds
+    fn out_of_bounds(&self) -> StoreCoords {
+        (self.dimensions.rows, self.dimensions.shelves, self.dimensions.zones).into()
+    }
 }
 
 impl From<&LimitStoreCoords> for StoreCoords {
@@ -85,20 +148,49 @@ impl From<&LimitStoreCoords> for StoreCoords {
 
 impl StepLite for LimitStoreCoords {
     fn add_one(&self) -> Self {
-        let coords = self.coords.next(self.max_idx).unwrap_or((self.max_idx,self.max_idx,self.max_idx).into());
+        let coords = self.coords.next(&self.dimensions).unwrap_or(self.out_of_bounds());
         
         LimitStoreCoords {
             coords, 
-            max_idx: self.max_idx
+            dimensions: self.dimensions
         }
     }
 
     fn sub_one(&self) -> Self {
-        let coords = self.coords.previous(self.max_idx).unwrap_or((self.max_idx,self.max_idx,self.max_idx).into());
+        let coords = self.coords.previous(&self.dimensions).unwrap_or(self.out_of_bounds());
 
         LimitStoreCoords {
             coords,
-            max_idx: self.max_idx
+            dimensions: self.dimensions
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_next_non_cubic() {
+        let dimensions = WarehouseDimensions::new(4, 30, 12);
+        assert_eq!(StoreCoords(0, 0, 10).next(&dimensions), Some((0, 0, 11).into()));
+        assert_eq!(StoreCoords(0, 0, 11).next(&dimensions), Some((0, 1, 0).into()));
+        assert_eq!(StoreCoords(0, 29, 11).next(&dimensions), Some((1, 0, 0).into()));
+        assert_eq!(StoreCoords(3, 29, 11).next(&dimensions), None);
+    }
+    
+    #[test]
+    fn test_previous_non_cubic() {
+        let dimensions = WarehouseDimensions::new(4, 30, 12);
+        assert_eq!(StoreCoords(0, 1, 0).previous(&dimensions), Some((0, 0, 11).into()));
+        assert_eq!(StoreCoords(1, 0, 0).previous(&dimensions), Some((0, 29, 11).into()));
+        assert_eq!(StoreCoords(0, 0, 0).previous(&dimensions), None);
+    }
+    
+    #[test]
+    fn test_zero_dimensions_rejected_on_load() {
+        let loaded: WarehouseDimensions = serde_json::from_str(r#"{"rows": 4, "shelves": 30, "zones": 12}"#).unwrap();
+        assert_eq!(loaded, WarehouseDimensions::new(4, 30, 12));
+        assert!(serde_json::from_str::<WarehouseDimensions>(r#"{"rows": 4, "shelves": 0, "zones": 12}"#).is_err());
+    }
+}
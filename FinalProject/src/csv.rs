@@ -0,0 +1,385 @@
+use std::io::{Read, Write};
+use thiserror::Error;
+use time::{Date, Time, UtcDateTime};
+use crate::coords::StoreCoords;
+use crate::warehouse::{ExpiryReport, ModificationError, Product, ProductCategory, Warehouse, WarehouseAllocator, WarehouseEntry};
+use crate::AnyOldProduct;
+
+pub const HEADER: [&str; 11] = ["row", "shelf", "zone", "identifier", "name", "amount", "category", "expiry", "max_row", "zone_count", "timestamp"];
+
+pub const EXPIRY_HEADER: [&str; 9] = ["bucket", "row", "shelf", "zone", "identifier", "name", "amount", "expiry", "days_left"];
+
+// Columns a row cannot do without, the rest may be left out of the header or blank
+const REQUIRED: [&str; 4] = ["identifier", "name", "amount", "category"];
+
+#[derive(Debug, Error)]
+pub enum CsvError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Header is missing column {0}")]
+    MissingColumn(String),
+    #[error("Unknown column {0}")]
+    UnknownColumn(String),
+    #[error("Unterminated quoted field starting on line {0}")]
+    UnterminatedQuote(usize),
+    #[error("Row has {found} fields, the header has {expected}")]
+    FieldCount {
+        expected: usize,
+        found: usize,
+    },
+    #[error("Invalid {column}: {reason}")]
+    InvalidField {
+        column: &'static str,
+        reason: String,
+    },
+    #[error(transparent)]
+    Modification(#[from] ModificationError),
+}
+
+#[derive(Debug)]
+pub struct CsvRowError {
+    /// Line in the file where the row starts
+    pub line: usize,
+    pub error: CsvError,
+}
+
+#[derive(Debug, Default)]
+pub struct CsvImportReport {
+    /// Where each imported row ended up, with its line
+    pub imported: Vec<(usize, StoreCoords)>,
+    pub errors: Vec<CsvRowError>,
+}
+
+/// One row per stored product, in store order, with a header row
+pub fn export_csv<I: Product>(warehouse: &Warehouse<I>, writer: &mut impl Write) -> std::io::Result<()> {
+    writeln!(writer, "{}", HEADER.join(","))?;
+
+    for (row, shelves) in warehouse.store().iter().enumerate() {
+        for (shelf, zones) in shelves.iter().enumerate() {
+            for (zone, entry) in zones.iter().enumerate() {
+                let WarehouseEntry::Some(product) = entry else {
+                    continue;
+                };
+
+                let (category, expiry, max_row, zone_count) = match product.quality() {
+                    ProductCategory::Normal => ("normal", String::new(), String::new(), String::new()),
+                    ProductCategory::Fragile { expiry_date, max_row } => ("fragile", expiry_date.to_string(), max_row.to_string(), String::new()),
+                    ProductCategory::Oversized { zone_count } => ("oversized", String::new(), String::new(), zone_count.to_string()),
+                };
+                let fields = [
+                    row.to_string(),
+                    shelf.to_string(),
+                    zone.to_string(),
+                    product.identifier().to_string(),
+                    product.name().clone(),
+                    product.amount().to_string(),
+                    category.to_string(),
+                    expiry,
+                    max_row,
+                    zone_count,
+                    format_timestamp(product.timestamp()),
+                ];
+                let fields: Vec<String> = fields.iter().map(|f| quote(f)).collect();
+                writeln!(writer, "{}", fields.join(","))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// One row per stack in the report, bucket by bucket, with a header row
+pub fn export_expiry_csv(report: &ExpiryReport, writer: &mut impl Write) -> std::io::Result<()> {
+    writeln!(writer, "{}", EXPIRY_HEADER.join(","))?;
+
+    for bucket in &report.buckets {
+        for stack in bucket.stacks() {
+            let fields = [
+                bucket.label.clone(),
+                stack.coords.0.to_string(),
+                stack.coords.1.to_string(),
+                stack.coords.2.to_string(),
+                stack.identifier.to_string(),
+                stack.name.clone(),
+                stack.amount.to_string(),
+                stack.expiry_date.to_string(),
+                report.days_left(stack).to_string(),
+            ];
+            let fields: Vec<String> = fields.iter().map(|f| quote(f)).collect();
+            writeln!(writer, "{}", fields.join(","))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds every row to the warehouse, at its coordinates or wherever the allocator decides when they are blank
+/// Rows go through the admission filters like any other product, rows that fail are skipped and reported
+/// Only a broken header or an unreadable file fails the whole import
+pub fn import_csv(
+    warehouse: &mut Warehouse<AnyOldProduct>,
+    reader: &mut impl Read,
+    allocator: &mut impl WarehouseAllocator<AnyOldProduct>,
+) -> Result<CsvImportReport, CsvError> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+    let mut records = parse_records(&input)?.into_iter();
+
+    let Some((_, header)) = records.next() else {
+        return Err(CsvError::MissingColumn(REQUIRED[0].to_string()));
+    };
+    let columns = header.iter()
+        .map(|name| HEADER.iter().position(|h| *h == name.trim()).ok_or_else(|| CsvError::UnknownColumn(name.clone())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(missing) = REQUIRED.iter().find(|r| !columns.iter().any(|c| HEADER[*c] == **r)) {
+        return Err(CsvError::MissingColumn(missing.to_string()));
+    }
+
+    let mut report = CsvImportReport::default();
+    for (line, fields) in records {
+        if fields.len() == 1 && fields[0].trim().is_empty() {
+            continue;
+        }
+
+        match import_row(warehouse, &columns, &fields, allocator) {
+            Ok(coords) => report.imported.push((line, coords)),
+            Err(error) => report.errors.push(CsvRowError { line, error }),
+        }
+    }
+
+    Ok(report)
+}
+
+fn import_row(
+    warehouse: &mut Warehouse<AnyOldProduct>,
+    columns: &[usize],
+    fields: &[String],
+    allocator: &mut impl WarehouseAllocator<AnyOldProduct>,
+) -> Result<StoreCoords, CsvError> {
+    if fields.len() != columns.len() {
+        return Err(CsvError::FieldCount { expected: columns.len(), found: fields.len() });
+    }
+
+    let mut values: [&str; HEADER.len()] = [""; HEADER.len()];
+    for (column, field) in columns.iter().zip(fields) {
+        values[*column] = field.trim();
+    }
+    let value = |column: &str| values[HEADER.iter().position(|h| *h == column).expect("Column is in the header")];
+
+    let identifier = parse_field(value("identifier"), "identifier")?;
+    let name = value("name").to_string();
+    if name.is_empty() {
+        return Err(CsvError::InvalidField { column: "name", reason: "cannot be empty".to_string() });
+    }
+    let amount = parse_field(value("amount"), "amount")?;
+    let quality = match value("category").to_lowercase().as_str() {
+        "normal" => ProductCategory::Normal,
+        "fragile" => ProductCategory::Fragile {
+            expiry_date: crate::maplidator_date(value("expiry").to_string())
+                .map_err(|reason| CsvError::InvalidField { column: "expiry", reason: reason.to_string() })?,
+            max_row: parse_field(value("max_row"), "max_row")?,
+        },
+        "oversized" => ProductCategory::Oversized { zone_count: parse_field(value("zone_count"), "zone_count")? },
+        other => return Err(CsvError::InvalidField { column: "category", reason: format!("unknown category {}", other) }),
+    };
+
+    let mut product = AnyOldProduct::new(identifier, name, amount, quality);
+    if !value("timestamp").is_empty() {
+        let timestamp = parse_timestamp(value("timestamp"))
+            .ok_or_else(|| CsvError::InvalidField { column: "timestamp", reason: format!("expected YYYY-MM-DDTHH:MM:SSZ, found {}", value("timestamp")) })?;
+        product.set_timestamp(timestamp);
+    }
+
+    let coords = [value("row"), value("shelf"), value("zone")];
+    if coords.iter().all(|c| c.is_empty()) {
+        warehouse.add_product(product, allocator)?;
+        return Ok(warehouse.search_by_id(&identifier).and_then(|c| c.last()).cloned().expect("Product was just added"));
+    }
+
+    let coords = StoreCoords(parse_field(coords[0], "row")?, parse_field(coords[1], "shelf")?, parse_field(coords[2], "zone")?);
+    warehouse.add_product(product, &mut FixedCoords(Some(coords.clone())))?;
+    Ok(coords)
+}
+
+// Places a single product at coordinates chosen beforehand
+struct FixedCoords(Option<StoreCoords>);
+
+impl<I: Product> WarehouseAllocator<I> for FixedCoords {
+    fn next(&mut self, _: &Warehouse<I>, _: &I) -> Option<StoreCoords> {
+        self.0.take()
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(value: &str, column: &'static str) -> Result<T, CsvError> {
+    if value.is_empty() {
+        return Err(CsvError::InvalidField { column, reason: "missing value".to_string() });
+    }
+    value.parse().map_err(|_| CsvError::InvalidField { column, reason: format!("not a valid number: {}", value) })
+}
+
+// Fields are quoted only when needed, doubling any quotes inside
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Splits the input into records, each with the line it starts on
+// Quoted fields may contain commas, doubled quotes and line breaks
+fn parse_records(input: &str) -> Result<Vec<(usize, Vec<String>)>, CsvError> {
+    let mut records = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        loop {
+            match chars.next() {
+                Some('"') if field.trim().is_empty() => {
+                    field.clear();
+                    loop {
+                        match chars.next() {
+                            Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                            Some('"') => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                field.push(c);
+                            }
+                            None => return Err(CsvError::UnterminatedQuote(start)),
+                        }
+                    }
+                }
+                Some(',') => fields.push(std::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') | None => {
+                    line += 1;
+                    fields.push(field);
+                    break;
+                }
+                Some(c) => field.push(c),
+            }
+        }
+        records.push((start, fields));
+    }
+
+    Ok(records)
+}
+
+/// Timestamps as written to CSV, in UTC with nanoseconds
+pub fn format_timestamp(timestamp: UtcDateTime) -> String {
+    format!("{}T{:02}:{:02}:{:02}.{:09}Z", timestamp.date(), timestamp.hour(), timestamp.minute(), timestamp.second(), timestamp.nanosecond())
+}
+
+fn parse_timestamp(value: &str) -> Option<UtcDateTime> {
+    let (date, time) = value.strip_suffix('Z')?.split_once('T')?;
+    let date: Date = crate::maplidator_date(date.to_string()).ok()?;
+    let (time, nanoseconds) = time.split_once('.').unwrap_or((time, "0"));
+    let mut parts = time.split(':').map(|p| p.parse::<u8>().ok());
+    let (hour, minute, second) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || nanoseconds.len() > 9 {
+        return None;
+    }
+    // Fractions are padded on the right, so .5 means half a second
+    let nanoseconds: u32 = format!("{:0<9}", nanoseconds).parse().ok()?;
+    Some(UtcDateTime::new(date, Time::from_hms_nano(hour, minute, second, nanoseconds).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    #[test]
+    fn test_round_trip() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        let expiry_date = Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        for (identifier, name, quality) in [
+            (1, "Bolts, hex \"M8\"", ProductCategory::Normal),
+            (2, "Beam", ProductCategory::Oversized { zone_count: 2 }),
+            (3, "Milk", ProductCategory::Fragile { expiry_date, max_row: 1 }),
+        ] {
+            let product = AnyOldProduct::new(identifier, name.to_string(), 10, quality);
+            assert!(warehouse.add_product(product, &mut allocator).is_ok());
+        }
+
+        let mut exported = Vec::new();
+        export_csv(&warehouse, &mut exported).unwrap();
+        let text = String::from_utf8(exported.clone()).unwrap();
+        assert!(text.starts_with("row,shelf,zone,identifier,name,amount,category,expiry,max_row,zone_count,timestamp\n0,0,0,1,\"Bolts, hex \"\"M8\"\"\",10,normal,,,,"));
+        assert!(text.contains("\n0,1,0,3,Milk,10,fragile,2030-01-01,1,,"));
+
+        let mut imported = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let report = import_csv(&mut imported, &mut exported.as_slice(), &mut allocator).unwrap();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.imported.len(), 3);
+
+        let mut reexported = Vec::new();
+        export_csv(&imported, &mut reexported).unwrap();
+        assert_eq!(String::from_utf8(reexported).unwrap(), text);
+    }
+
+    #[test]
+    fn test_expiry_export() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for (identifier, day) in [(1, 2), (2, 20)] {
+            let expiry_date = Date::from_calendar_date(2030, time::Month::January, day).unwrap();
+            let product = AnyOldProduct::new(identifier, format!("Milk {}", identifier), 10, ProductCategory::Fragile { expiry_date, max_row: 1 });
+            warehouse.add_product(product, &mut allocator).unwrap();
+        }
+        let as_of = Date::from_calendar_date(2030, time::Month::January, 5).unwrap();
+        let report = warehouse.expiry_report(as_of, &"7".parse().unwrap());
+
+        let mut exported = Vec::new();
+        export_expiry_csv(&report, &mut exported).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), "\
+bucket,row,shelf,zone,identifier,name,amount,expiry,days_left
+expired,0,0,0,1,Milk 1,10,2030-01-02,-3
+later,0,0,1,2,Milk 2,10,2030-01-20,15
+");
+    }
+
+    #[test]
+    fn test_row_errors() {
+        let input = "\
+identifier,name,amount,category,row,shelf,zone,expiry,max_row
+1,Bolts,5,normal,1,1,1,,
+2,Nuts,five,normal,,,,,
+3,Milk,2,fragile,,,,2030-02-30,1
+4,\"Washers
+large\",3,normal,1,1,1,,
+5,Pins,1,normal,,,,,
+6,Tape,1,normal,,,
+";
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let report = import_csv(&mut warehouse, &mut input.as_bytes(), &mut WarehouseAllocatorClosestFirstEfficient).unwrap();
+
+        assert_eq!(report.imported, vec![(2, StoreCoords(1, 1, 1)), (7, StoreCoords(0, 0, 0))]);
+        let errors: Vec<usize> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(errors, vec![3, 4, 5, 8]);
+        assert!(matches!(report.errors[0].error, CsvError::InvalidField { column: "amount", .. }));
+        assert!(matches!(report.errors[1].error, CsvError::InvalidField { column: "expiry", .. }));
+        assert!(matches!(report.errors[2].error, CsvError::Modification(ModificationError::Occupied)));
+        assert!(matches!(report.errors[3].error, CsvError::FieldCount { expected: 9, found: 7 }));
+
+        let missing = "identifier,name,amount\n1,Bolts,5\n";
+        assert!(matches!(import_csv(&mut warehouse, &mut missing.as_bytes(), &mut WarehouseAllocatorClosestFirstEfficient), Err(CsvError::MissingColumn(c)) if c == "category"));
+    }
+
+    #[test]
+    fn test_timestamp_round_trip() {
+        let timestamp = UtcDateTime::new(Date::from_calendar_date(2026, time::Month::March, 4).unwrap(), Time::from_hms_nano(5, 6, 7, 800).unwrap());
+        assert_eq!(format_timestamp(timestamp), "2026-03-04T05:06:07.000000800Z");
+        assert_eq!(parse_timestamp(&format_timestamp(timestamp)), Some(timestamp));
+        assert_eq!(parse_timestamp("2026-03-04T05:06:07Z").map(|t| t.second()), Some(7));
+        assert_eq!(parse_timestamp("2026-03-04 05:06:07"), None);
+    }
+}
@@ -0,0 +1,362 @@
+use serde_derive::{Deserialize, Serialize};
+use time::{Date, Duration, UtcDateTime};
+use crate::warehouse::{FilterError, Product, ProductCategory, Warehouse, WarehouseAdmissionFilter, WarehouseEntry};
+
+// Dates are compared against the current day unless a fixed one is given
+fn reference_date(as_of: Option<Date>) -> Date {
+    as_of.unwrap_or_else(|| UtcDateTime::now().date())
+}
+
+// None when the limit falls outside the supported date range
+fn shelf_life_limit(date: Date, days: i64) -> Option<Date> {
+    days.checked_mul(86_400).and_then(|seconds| date.checked_add(Duration::seconds(seconds)))
+}
+
+/// Data form of the built-in filters, used for config files and warehouse snapshots
+/// Rules are always evaluated against the current date
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterRule {
+    RejectExpired,
+    MaxStacksPerIdentifier {
+        max_stacks: usize,
+    },
+    MinShelfLife {
+        days: i64,
+    },
+    UniqueNames,
+}
+
+impl FilterRule {
+    pub fn instantiate<I: Product>(&self) -> Result<Box<dyn WarehouseAdmissionFilter<I>>, FilterError> {
+        Ok(match self {
+            FilterRule::RejectExpired => Box::new(RejectExpired { as_of: None }),
+            FilterRule::MaxStacksPerIdentifier { max_stacks } => Box::new(MaxStacksPerIdentifier { max_stacks: *max_stacks }),
+            FilterRule::MinShelfLife { days } => {
+                if shelf_life_limit(reference_date(None), *days).is_none() {
+                    return Err(FilterError::InvalidRule(format!("{} days of shelf life is out of range", days)));
+                }
+                Box::new(MinShelfLife { days: *days, as_of: None })
+            }
+            FilterRule::UniqueNames => Box::new(UniqueNames),
+        })
+    }
+
+    // Config files are a JSON list of rules
+    pub fn load_config(reader: &mut impl std::io::BufRead) -> Result<Vec<FilterRule>, serde_json::Error> {
+        serde_json::from_reader(reader)
+    }
+}
+
+// (De)serializes the active filters of a warehouse as their rules
+pub mod persisted {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use crate::warehouse::{FilterError, Product, WarehouseAdmissionFilter};
+    use super::FilterRule;
+
+    pub fn serialize<S, I>(filters: &[Box<dyn WarehouseAdmissionFilter<I>>], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        I: Product,
+    {
+        let rules: Vec<FilterRule> = filters.iter().filter_map(|f| f.rule()).collect();
+        rules.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, I>(deserializer: D) -> Result<Vec<Box<dyn WarehouseAdmissionFilter<I>>>, D::Error>
+    where
+        D: Deserializer<'de>,
+        I: Product,
+    {
+        // Names are checked as in add_filter, so removing a filter by name stays unambiguous
+        let rules = Vec::<FilterRule>::deserialize(deserializer)?;
+        let mut filters: Vec<Box<dyn WarehouseAdmissionFilter<I>>> = Vec::with_capacity(rules.len());
+        for rule in &rules {
+            let filter = rule.instantiate().map_err(D::Error::custom)?;
+            if filters.iter().any(|f| f.name() == filter.name()) {
+                return Err(D::Error::custom(FilterError::DuplicateName(filter.name().to_string())));
+            }
+            filters.push(filter);
+        }
+        Ok(filters)
+    }
+}
+
+/// Rejects Fragile products whose expiry date has already passed
+pub struct RejectExpired {
+    pub as_of: Option<Date>,
+}
+
+impl<I: Product> WarehouseAdmissionFilter<I> for RejectExpired {
+    fn name(&self) -> &str {
+        "reject-expired"
+    }
+
+    fn description(&self) -> String {
+        "Fragile products must not be expired".to_string()
+    }
+
+    fn rule(&self) -> Option<FilterRule> {
+        // A fixed date cannot be expressed as a rule
+        self.as_of.is_none().then_some(FilterRule::RejectExpired)
+    }
+
+    fn check(&mut self, _warehouse: &Warehouse<I>, product: &I) -> Result<(), String> {
+        let today = reference_date(self.as_of);
+        match product.quality() {
+            ProductCategory::Fragile { expiry_date, .. } if *expiry_date < today => {
+                Err(format!("Product expired on {}", expiry_date))
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+/// Caps the number of stacks stored for any single identifier
+pub struct MaxStacksPerIdentifier {
+    pub max_stacks: usize,
+}
+
+impl<I: Product> WarehouseAdmissionFilter<I> for MaxStacksPerIdentifier {
+    fn name(&self) -> &str {
+        "max-stacks-per-identifier"
+    }
+
+    fn description(&self) -> String {
+        format!("At most {} stacks per identifier", self.max_stacks)
+    }
+
+    fn rule(&self) -> Option<FilterRule> {
+        Some(FilterRule::MaxStacksPerIdentifier { max_stacks: self.max_stacks })
+    }
+
+    fn check(&mut self, warehouse: &Warehouse<I>, product: &I) -> Result<(), String> {
+        let stacks = warehouse.search_by_id(product.identifier()).map_or(0, Vec::len);
+        if stacks >= self.max_stacks {
+            return Err(format!("Identifier {} already has {} stacks", product.identifier(), stacks));
+        }
+        Ok(())
+    }
+}
+
+/// Requires Fragile products to have at least the given number of days before expiring
+pub struct MinShelfLife {
+    pub days: i64,
+    pub as_of: Option<Date>,
+}
+
+impl<I: Product> WarehouseAdmissionFilter<I> for MinShelfLife {
+    fn name(&self) -> &str {
+        "min-shelf-life"
+    }
+
+    fn description(&self) -> String {
+        format!("Fragile products must have at least {} days of shelf life", self.days)
+    }
+
+    fn rule(&self) -> Option<FilterRule> {
+        self.as_of.is_none().then_some(FilterRule::MinShelfLife { days: self.days })
+    }
+
+    fn check(&mut self, _warehouse: &Warehouse<I>, product: &I) -> Result<(), String> {
+        let Some(limit) = shelf_life_limit(reference_date(self.as_of), self.days) else {
+            return Err(format!("Shelf life of {} days is out of range", self.days));
+        };
+        match product.quality() {
+            ProductCategory::Fragile { expiry_date, .. } if *expiry_date < limit => {
+                Err(format!("Product expires on {}, before {}", expiry_date, limit))
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+/// Rejects products reusing the name of a stored product with a different identifier
+pub struct UniqueNames;
+
+impl<I: Product> WarehouseAdmissionFilter<I> for UniqueNames {
+    fn name(&self) -> &str {
+        "unique-names"
+    }
+
+    fn description(&self) -> String {
+        "Names cannot be shared between identifiers".to_string()
+    }
+
+    fn rule(&self) -> Option<FilterRule> {
+        Some(FilterRule::UniqueNames)
+    }
+
+    fn check(&mut self, warehouse: &Warehouse<I>, product: &I) -> Result<(), String> {
+        let Some(coords) = warehouse.search_by_name(product.name()).and_then(|c| c.first()) else {
+            return Ok(());
+        };
+
+        if let Ok(WarehouseEntry::Some(existing)) = warehouse.get_product_ref(coords)
+            && existing.identifier() != product.identifier() {
+            return Err(format!("Name {} is already used by identifier {}", product.name(), existing.identifier()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::warehouse::{ModificationError, SnapshotError};
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    // Wraps a closure as a named filter, standing in for filters defined outside the crate
+    struct FnFilter<F> {
+        name: String,
+        check: F,
+    }
+
+    impl<F> FnFilter<F> {
+        fn new(name: &str, check: F) -> Self {
+            FnFilter {
+                name: name.to_string(),
+                check,
+            }
+        }
+    }
+
+    impl<I, F> WarehouseAdmissionFilter<I> for FnFilter<F>
+    where
+        I: Product,
+        F: FnMut(&Warehouse<I>, &I) -> Result<(), String>,
+    {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn description(&self) -> String {
+            "Custom rule".to_string()
+        }
+
+        fn check(&mut self, warehouse: &Warehouse<I>, product: &I) -> Result<(), String> {
+            (self.check)(warehouse, product)
+        }
+    }
+
+    fn fragile(identifier: i64, expiry_date: Date) -> AnyOldProduct {
+        AnyOldProduct::new(identifier, "Milk".to_string(), 10, ProductCategory::Fragile { expiry_date, max_row: 3 })
+    }
+
+    #[test]
+    fn test_reject_expired() {
+        let today = Date::from_calendar_date(2026, time::Month::September, 1).unwrap();
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(warehouse.add_filter(Box::new(RejectExpired { as_of: Some(today) })).is_ok());
+
+        let result = warehouse.add_product(fragile(1, today - Duration::days(1)), &mut WarehouseAllocatorClosestFirstEfficient);
+        assert!(matches!(result, Err(ModificationError::NotAllowed { filter, .. }) if filter == "reject-expired"));
+        assert!(warehouse.add_product(fragile(1, today), &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+    }
+
+    #[test]
+    fn test_min_shelf_life() {
+        let today = Date::from_calendar_date(2026, time::Month::September, 1).unwrap();
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(warehouse.add_filter(Box::new(MinShelfLife { days: 7, as_of: Some(today) })).is_ok());
+
+        let result = warehouse.add_product(fragile(1, today + Duration::days(6)), &mut WarehouseAllocatorClosestFirstEfficient);
+        assert!(matches!(result, Err(ModificationError::NotAllowed { filter, .. }) if filter == "min-shelf-life"));
+        assert!(warehouse.add_product(fragile(1, today + Duration::days(7)), &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+    }
+
+    #[test]
+    fn test_max_stacks_and_unique_names() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(warehouse.add_filter(Box::new(MaxStacksPerIdentifier { max_stacks: 1 })).is_ok());
+        assert!(warehouse.add_filter(Box::new(UniqueNames)).is_ok());
+
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 10, ProductCategory::Normal);
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 10, ProductCategory::Normal);
+        let result = warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient);
+        assert!(matches!(result, Err(ModificationError::NotAllowed { filter, .. }) if filter == "max-stacks-per-identifier"));
+
+        let product = AnyOldProduct::new(2, "Bolts".to_string(), 10, ProductCategory::Normal);
+        let result = warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient);
+        assert!(matches!(result, Err(ModificationError::NotAllowed { filter, .. }) if filter == "unique-names"));
+    }
+
+    #[test]
+    fn test_filter_management() {
+        let mut warehouse = Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(warehouse.add_filter(Box::new(UniqueNames)).is_ok());
+        assert!(warehouse.add_filter(Box::new(UniqueNames)).is_err());
+        assert!(warehouse.add_filter(Box::new(FnFilter::new("no-empty-stacks", |_: &Warehouse<AnyOldProduct>, p: &AnyOldProduct| {
+            if p.amount() == 0 { Err("Empty stack".to_string()) } else { Ok(()) }
+        }))).is_ok());
+
+        assert_eq!(warehouse.list_filters().map(|f| f.name()).collect::<Vec<_>>(), vec!["unique-names", "no-empty-stacks"]);
+        assert!(warehouse.remove_filter("unique-names").is_some());
+        assert!(warehouse.remove_filter("unique-names").is_none());
+        assert_eq!(warehouse.list_filters().len(), 1);
+    }
+
+    #[test]
+    fn test_rules_survive_snapshot() {
+        let mut warehouse = Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(warehouse.add_rule(FilterRule::MaxStacksPerIdentifier { max_stacks: 1 }).is_ok());
+        assert!(warehouse.add_rule(FilterRule::UniqueNames).is_ok());
+        assert!(warehouse.add_filter(Box::new(FnFilter::new("custom", |_: &Warehouse<AnyOldProduct>, _: &AnyOldProduct| Ok(())))).is_ok());
+
+        let mut buffer = Vec::new();
+        warehouse.to_json(&mut buffer).unwrap();
+        let (mut restored, report) = Warehouse::<AnyOldProduct>::from_json(&mut buffer.as_slice()).unwrap();
+        assert!(report.is_ok());
+
+        let rules: Vec<_> = restored.list_filters().filter_map(|f| f.rule()).collect();
+        assert_eq!(rules, vec![FilterRule::MaxStacksPerIdentifier { max_stacks: 1 }, FilterRule::UniqueNames]);
+        assert_eq!(restored.list_filters().len(), 2);
+
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 10, ProductCategory::Normal);
+        assert!(restored.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 10, ProductCategory::Normal);
+        assert!(restored.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_rules_rejected_on_load() {
+        let mut warehouse = Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(warehouse.add_rule(FilterRule::UniqueNames).is_ok());
+        let mut buffer = Vec::new();
+        warehouse.to_json(&mut buffer).unwrap();
+
+        let mut snapshot: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        snapshot["warehouse"]["filters"] = serde_json::json!(["UniqueNames", "RejectExpired", "UniqueNames"]);
+        let result = Warehouse::<AnyOldProduct>::from_json(&mut snapshot.to_string().as_bytes());
+        assert!(matches!(result, Err(SnapshotError::Invalid(message)) if message.contains("unique-names")));
+    }
+
+    #[test]
+    fn test_out_of_range_shelf_life_rejected() {
+        let mut warehouse = Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(4, 4, 4));
+        assert!(matches!(warehouse.add_rule(FilterRule::MinShelfLife { days: 3_000_000 }), Err(FilterError::InvalidRule(_))));
+        assert!(matches!(warehouse.add_rule(FilterRule::MinShelfLife { days: i64::MAX }), Err(FilterError::InvalidRule(_))));
+
+        let mut buffer = Vec::new();
+        warehouse.to_json(&mut buffer).unwrap();
+        let mut snapshot: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        snapshot["warehouse"]["filters"] = serde_json::json!([{"MinShelfLife": {"days": 3_000_000}}]);
+        let result = Warehouse::<AnyOldProduct>::from_json(&mut snapshot.to_string().as_bytes());
+        assert!(matches!(result, Err(SnapshotError::Invalid(message)) if message.contains("3000000")));
+    }
+
+    #[test]
+    fn test_load_config() {
+        let config = r#"["RejectExpired", {"MinShelfLife": {"days": 3}}, {"MaxStacksPerIdentifier": {"max_stacks": 2}}]"#;
+        let rules = FilterRule::load_config(&mut config.as_bytes()).unwrap();
+        assert_eq!(rules, vec![
+            FilterRule::RejectExpired,
+            FilterRule::MinShelfLife { days: 3 },
+            FilterRule::MaxStacksPerIdentifier { max_stacks: 2 },
+        ]);
+    }
+}
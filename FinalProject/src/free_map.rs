@@ -1,109 +1,291 @@
 use std::ops::{RangeInclusive};
 use rangemap::RangeInclusiveSet;
 use serde_derive::{Deserialize, Serialize};
-use crate::coords::{LimitStoreCoords, StoreCoords};
+use crate::coords::{LimitStoreCoords, StoreCoords, WarehouseDimensions, MAX_ZONES};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "FreeMapData")]
 pub struct FreeMap {
-    store_max_idx: usize,
+    dimensions: WarehouseDimensions,
     map: RangeInclusiveSet<LimitStoreCoords>,
+    // Derived from the map, so it is rebuilt on load instead of saved
+    #[serde(skip)]
+    runs: RunIndex,
+}
+
+// Saved form of the free map
+#[derive(Deserialize)]
+struct FreeMapData {
+    dimensions: WarehouseDimensions,
+    map: RangeInclusiveSet<LimitStoreCoords>,
+}
+
+// The run index is sized from the dimensions, so they and the ranges are checked before building it
+impl TryFrom<FreeMapData> for FreeMap {
+    type Error = String;
+
+    fn try_from(data: FreeMapData) -> Result<Self, Self::Error> {
+        let dimensions = data.dimensions;
+        if !dimensions.zone_count().is_some_and(|zones| zones > 0 && zones <= MAX_ZONES) {
+            return Err(format!("free map of {} is larger than {} zones", dimensions, MAX_ZONES));
+        }
+        let outside = data.map.iter()
+            .any(|range| !dimensions.contains(&range.start().into()) || !dimensions.contains(&range.end().into()));
+        if outside {
+            return Err(format!("free map has ranges outside {}", dimensions));
+        }
+        Ok(FreeMap::with_map(dimensions, data.map))
+    }
+}
+
+/// Limits for find_run, unbounded when None
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunConstraints {
+    pub rows: Option<RangeInclusive<usize>>,
+    pub shelves: Option<RangeInclusive<usize>>,
+}
+
+impl RunConstraints {
+    pub fn rows(rows: RangeInclusive<usize>) -> Self {
+        RunConstraints { rows: Some(rows), shelves: None }
+    }
+    
+    fn allows(&self, coords: &StoreCoords) -> bool {
+        self.rows.as_ref().is_none_or(|rows| rows.contains(&coords.0))
+            && self.shelves.as_ref().is_none_or(|shelves| shelves.contains(&coords.1))
+    }
+}
+
+// Longest free run on each shelf, in a max segment tree over the shelves in row-major order
+// Finding the first shelf with a long enough run takes logarithmic time, however fragmented the map is
+#[derive(Default, Clone)]
+struct RunIndex {
+    leaves: usize,
+    tree: Vec<usize>,
+}
+
+impl RunIndex {
+    fn new(shelves: usize) -> Self {
+        let leaves = shelves.next_power_of_two();
+        RunIndex { leaves, tree: vec![0; leaves * 2] }
+    }
+
+    fn set(&mut self, shelf: usize, longest: usize) {
+        let mut node = shelf + self.leaves;
+        self.tree[node] = longest;
+        while node > 1 {
+            node /= 2;
+            self.tree[node] = self.tree[node * 2].max(self.tree[node * 2 + 1]);
+        }
+    }
+
+    // First shelf between both, inclusive, with a run of at least len
+    fn first_at_least(&self, from: usize, to: usize, len: usize) -> Option<usize> {
+        self.search(1, 0, self.leaves - 1, from, to, len)
+    }
+
+    fn search(&self, node: usize, node_from: usize, node_to: usize, from: usize, to: usize, len: usize) -> Option<usize> {
+        if node_to < from || node_from > to || self.tree[node] < len {
+            return None;
+        }
+        if node_from == node_to {
+            return Some(node_from);
+        }
+        let middle = (node_from + node_to) / 2;
+        self.search(node * 2, node_from, middle, from, to, len)
+            .or_else(|| self.search(node * 2 + 1, middle + 1, node_to, from, to, len))
+    }
 }
 
 impl FreeMap {
-    pub fn new(store_max_idx: usize) -> FreeMap {
+    pub fn new(dimensions: WarehouseDimensions) -> FreeMap {
         let mut map = RangeInclusiveSet::new();
         map.insert(
-            LimitStoreCoords::from_with_max((0,0,0).into(),store_max_idx)
+            LimitStoreCoords::from_with_dimensions((0,0,0).into(),dimensions)
                 ..=
-                LimitStoreCoords::from_with_max((store_max_idx-1,store_max_idx-1,store_max_idx-1).into(),store_max_idx)
+                LimitStoreCoords::from_with_dimensions(dimensions.last(),dimensions)
         );
         
-        FreeMap {
-            store_max_idx,
+        FreeMap::with_map(dimensions, map)
+    }
+
+    fn with_map(dimensions: WarehouseDimensions, map: RangeInclusiveSet<LimitStoreCoords>) -> FreeMap {
+        let mut free_map = FreeMap {
+            dimensions,
             map,
-        }
+            runs: RunIndex::new(dimensions.rows * dimensions.shelves),
+        };
+        free_map.reindex(&(0, 0, 0).into(), &dimensions.last());
+        free_map
     }
 
     pub fn occupy_single(&mut self, place: StoreCoords) -> bool {
-        let place = LimitStoreCoords::from_with_max(place,self.store_max_idx);
+        let place = LimitStoreCoords::from_with_dimensions(place,self.dimensions);
         if !self.map.contains(&place) {
             return false;
         }
 
-        self.map.remove(place.clone()..=place);
+        self.map.remove(place.clone()..=place.clone());
+        self.reindex(&(&place).into(), &(&place).into());
         true
     }
     
     pub fn occupy_range(&mut self, range: RangeInclusive<StoreCoords>) -> bool
     {
         let range = 
-            LimitStoreCoords::from_with_max(range.start().clone(), self.store_max_idx)
+            LimitStoreCoords::from_with_dimensions(range.start().clone(), self.dimensions)
             ..=
-            LimitStoreCoords::from_with_max(range.end().clone(), self.store_max_idx);
+            LimitStoreCoords::from_with_dimensions(range.end().clone(), self.dimensions);
         
         if !self.map.overlaps(&range) {
             return false;
         }
         
-        self.map.remove(range);
+        self.map.remove(range.clone());
+        self.reindex(&range.start().into(), &range.end().into());
         true
     }
     
     pub fn free_single(&mut self, place: StoreCoords) -> bool {
-        let place = LimitStoreCoords::from_with_max(place,self.store_max_idx);
+        let place = LimitStoreCoords::from_with_dimensions(place,self.dimensions);
         if self.map.contains(&place) {
             return false;
         }
         
-        self.map.insert(place.clone()..=place);
+        self.map.insert(place.clone()..=place.clone());
+        self.reindex(&(&place).into(), &(&place).into());
         true
     }
     
     pub fn free_range(&mut self, range: RangeInclusive<StoreCoords>) -> bool {
         let range =
-            LimitStoreCoords::from_with_max(range.start().clone(), self.store_max_idx)
+            LimitStoreCoords::from_with_dimensions(range.start().clone(), self.dimensions)
                 ..=
-                LimitStoreCoords::from_with_max(range.end().clone(), self.store_max_idx);
+                LimitStoreCoords::from_with_dimensions(range.end().clone(), self.dimensions);
         if self.map.overlaps(&range) {
             return false;
         }
         
-        self.map.insert(range);
+        self.map.insert(range.clone());
+        self.reindex(&range.start().into(), &range.end().into());
         true
     }
     
+    pub fn is_free(&self, place: &StoreCoords) -> bool {
+        self.map.contains(&LimitStoreCoords::from_with_dimensions(place.clone(), self.dimensions))
+    }
+    
+    #[allow(unused)]
     pub fn iter(&self) -> impl Iterator<Item=RangeInclusive<StoreCoords>> {
         self.map.iter().map(|i| i.start().into()..=i.end().into())
     }
     
-    #[allow(unused)]
     pub fn iter_from(&self, place: StoreCoords) -> impl Iterator<Item=RangeInclusive<StoreCoords>> {
-        let place = LimitStoreCoords::from_with_max(place,self.store_max_idx);
-        let max = LimitStoreCoords::from_with_max((self.store_max_idx-1,self.store_max_idx-1,self.store_max_idx-1).into(),self.store_max_idx);
+        let place = LimitStoreCoords::from_with_dimensions(place,self.dimensions);
+        let max = LimitStoreCoords::from_with_dimensions(self.dimensions.last(),self.dimensions);
         self.map.overlapping(place..=max).map(|i| i.start().into()..=i.end().into())
     }
+    
+    /// Start of the first free span of len zones, in row-major order, that stays on a single shelf
+    pub fn find_run(&self, len: usize, constraints: &RunConstraints) -> Option<StoreCoords> {
+        if len == 0 || len > self.dimensions.zones {
+            return None;
+        }
+        let rows = constraints.rows.clone().unwrap_or(0..=usize::MAX);
+        let shelves = constraints.shelves.clone().unwrap_or(0..=usize::MAX);
+        let last_row = (*rows.end()).min(self.dimensions.rows - 1);
+        let last_shelf = (*shelves.end()).min(self.dimensions.shelves - 1);
+        if shelves.start() > &last_shelf {
+            return None;
+        }
+        
+        for row in *rows.start()..=last_row {
+            let base = row * self.dimensions.shelves;
+            if let Some(shelf) = self.runs.first_at_least(base + shelves.start(), base + last_shelf, len) {
+                let shelf = shelf - base;
+                return self.shelf_runs(row, shelf)
+                    .find(|(start, end)| end - start + 1 >= len)
+                    .map(|(start, _)| StoreCoords(row, shelf, start));
+            }
+        }
+        None
+    }
+    
+    /// Where a product of len zones goes to sit at the end of the last free span that fits it, the furthest from the start
+    pub fn find_last_run(&self, len: usize, constraints: &RunConstraints) -> Option<StoreCoords> {
+        self.runs(constraints)
+            .filter(|(_, run)| *run >= len)
+            .last()
+            .map(|(start, run)| StoreCoords(start.0, start.1, start.2 + run - len))
+    }
+    
+    /// Every free span inside the bounds, split at shelf boundaries, as its start and length in row-major order
+    pub fn runs(&self, constraints: &RunConstraints) -> impl Iterator<Item = (StoreCoords, usize)> + use<'_> {
+        let dimensions = self.dimensions;
+        let constraints = constraints.clone();
+        self.map.iter()
+            .flat_map(move |range| {
+                let end: StoreCoords = range.end().into();
+                let mut next = Some(StoreCoords::from(range.start()));
+                std::iter::from_fn(move || {
+                    let start = next.take().filter(|start| *start <= end)?;
+                    let shelf_end = StoreCoords(start.0, start.1, dimensions.zones - 1).min(end.clone());
+                    next = shelf_end.next(&dimensions);
+                    let len = shelf_end.2 - start.2 + 1;
+                    Some((start, len))
+                })
+            })
+            .filter(move |(start, _)| constraints.allows(start))
+    }
+    
+    // Free runs on a shelf as first and last zone
+    fn shelf_runs(&self, row: usize, shelf: usize) -> impl Iterator<Item = (usize, usize)> {
+        let first = StoreCoords(row, shelf, 0);
+        let last = StoreCoords(row, shelf, self.dimensions.zones - 1);
+        let shelf_range = LimitStoreCoords::from_with_dimensions(first.clone(), self.dimensions)
+            ..=
+            LimitStoreCoords::from_with_dimensions(last.clone(), self.dimensions);
+        self.map.overlapping(shelf_range).map(move |range| {
+            let start: StoreCoords = range.start().into();
+            let end: StoreCoords = range.end().into();
+            (start.max(first.clone()).2, end.min(last.clone()).2)
+        })
+    }
+    
+    // Recomputes the longest run of every shelf touched by the zones between both coordinates
+    fn reindex(&mut self, start: &StoreCoords, end: &StoreCoords) {
+        let shelves = self.dimensions.shelves;
+        for shelf in start.0 * shelves + start.1..=end.0 * shelves + end.1 {
+            let longest = self.shelf_runs(shelf / shelves, shelf % shelves)
+                .map(|(start, end)| end - start + 1)
+                .max()
+                .unwrap_or(0);
+            self.runs.set(shelf, longest);
+        }
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     #[test]
     fn test_persist() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         assert_eq!(map.occupy_single((0,0,0).into()), true);
         assert_eq!(map.occupy_single((0,0,0).into()), false);
     }
     
     #[test]
     fn test_persist_range() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         assert_eq!(map.occupy_range((1,0,0).into()..=(3,0,0).into()), true);
         assert_eq!(map.occupy_range((1,0,0).into()..=(2,0,0).into()), false);
     }
     
     #[test]
     fn test_iter_single() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         assert_eq!(map.occupy_single((1,0,0).into()), true);
         
         let mut iter = map.iter();
@@ -115,7 +297,7 @@ mod tests {
     
     #[test]
     fn test_iter_single_max() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         map.occupy_single((0,0,9).into());
         
         let mut iter = map.iter();
@@ -126,7 +308,7 @@ mod tests {
     
     #[test]
     fn test_iter_range_max() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         map.occupy_range((0,0,5).into()..=(0,0,9).into());
         
         let mut iter = map.iter();
@@ -137,7 +319,7 @@ mod tests {
     
     #[test]
     fn test_iter_range() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         assert_eq!(map.occupy_range((1,0,0).into()..=(1,9,9).into()), true);
 
         let mut iter = map.iter();
@@ -149,8 +331,92 @@ mod tests {
     
     #[test]
     fn test_free_single() {
-        let mut map = FreeMap::new(10);
+        let mut map = FreeMap::new(WarehouseDimensions::new(10, 10, 10));
         assert_eq!(map.occupy_single((1,0,0).into()), true);
         assert_eq!(map.free_single((1,0,0).into()), true);
     }
-}
+    
+    #[test]
+    fn test_find_run_stays_on_shelf() {
+        let mut map = FreeMap::new(WarehouseDimensions::new(2, 3, 4));
+        assert_eq!(map.find_run(4, &RunConstraints::default()), Some((0,0,0).into()));
+        assert_eq!(map.find_run(5, &RunConstraints::default()), None);
+        
+        // Zones 0,0,2 to 0,1,1 are free, but split across two shelves
+        map.occupy_range((0,0,0).into()..=(0,0,1).into());
+        map.occupy_range((0,1,2).into()..=(0,1,3).into());
+        assert_eq!(map.find_run(2, &RunConstraints::default()), Some((0,0,2).into()));
+        assert_eq!(map.find_run(3, &RunConstraints::default()), Some((0,2,0).into()));
+        map.occupy_single((0,2,1).into());
+        assert_eq!(map.find_run(3, &RunConstraints::default()), Some((1,0,0).into()));
+        
+        map.free_range((0,0,0).into()..=(0,0,1).into());
+        assert_eq!(map.find_run(3, &RunConstraints::default()), Some((0,0,0).into()));
+    }
+    
+    #[test]
+    fn test_find_run_constraints() {
+        let mut map = FreeMap::new(WarehouseDimensions::new(3, 3, 3));
+        map.occupy_single((0,0,1).into());
+        let shelves = RunConstraints { rows: None, shelves: Some(1..=2) };
+        assert_eq!(map.find_run(2, &shelves), Some((0,1,0).into()));
+        let rows = RunConstraints::rows(1..=1);
+        assert_eq!(map.find_run(3, &rows), Some((1,0,0).into()));
+        let both = RunConstraints { rows: Some(2..=9), shelves: Some(2..=9) };
+        assert_eq!(map.find_run(1, &both), Some((2,2,0).into()));
+        
+        map.occupy_range((0,0,0).into()..=(0,2,2).into());
+        assert_eq!(map.find_run(1, &RunConstraints::rows(0..=0)), None);
+        assert_eq!(map.find_run(1, &RunConstraints { rows: None, shelves: Some(3..=4) }), None);
+    }
+    
+    #[test]
+    fn test_find_run_fragmented() {
+        let mut map = FreeMap::new(WarehouseDimensions::new(30, 30, 30));
+        // Every other zone taken, so no two free zones are next to each other
+        for row in 0..30 {
+            for shelf in 0..30 {
+                for zone in (0..30).step_by(2) {
+                    map.occupy_single((row, shelf, zone).into());
+                }
+            }
+        }
+        assert_eq!(map.find_run(1, &RunConstraints::default()), Some((0,0,1).into()));
+        assert_eq!(map.find_run(2, &RunConstraints::default()), None);
+        
+        map.free_single((27,12,20).into());
+        assert_eq!(map.find_run(3, &RunConstraints::default()), Some((27,12,19).into()));
+        
+        let reloaded: FreeMap = serde_json::from_value(serde_json::to_value(&map).unwrap()).unwrap();
+        assert_eq!(reloaded.find_run(3, &RunConstraints::default()), Some((27,12,19).into()));
+    }
+    
+    #[test]
+    fn test_runs_split_at_shelves() {
+        let mut map = FreeMap::new(WarehouseDimensions::new(2, 2, 4));
+        map.occupy_range((0,0,0).into()..=(0,0,1).into());
+        map.occupy_single((1,0,2).into());
+        let runs: Vec<_> = map.runs(&RunConstraints::default()).collect();
+        assert_eq!(runs, vec![
+            ((0,0,2).into(), 2), ((0,1,0).into(), 4), ((1,0,0).into(), 2), ((1,0,3).into(), 1), ((1,1,0).into(), 4),
+        ]);
+        let runs: Vec<_> = map.runs(&RunConstraints { rows: Some(1..=1), shelves: Some(0..=0) }).collect();
+        assert_eq!(runs, vec![((1,0,0).into(), 2), ((1,0,3).into(), 1)]);
+        
+        assert_eq!(map.find_last_run(3, &RunConstraints::default()), Some((1,1,1).into()));
+        assert_eq!(map.find_last_run(2, &RunConstraints::rows(1..=1)), Some((1,1,2).into()));
+        assert_eq!(map.find_last_run(2, &RunConstraints { rows: Some(1..=1), shelves: Some(0..=0) }), Some((1,0,0).into()));
+        assert_eq!(map.find_last_run(3, &RunConstraints { rows: Some(1..=1), shelves: Some(0..=0) }), None);
+    }
+    
+    #[test]
+    fn test_iter_non_cubic() {
+        let mut map = FreeMap::new(WarehouseDimensions::new(4, 30, 12));
+        assert!(map.occupy_range((0,0,6).into()..=(0,0,11).into()));
+        
+        let mut iter = map.iter();
+        assert_eq!(iter.next(), Some((0,0,0).into()..=(0,0,5).into()));
+        assert_eq!(iter.next(), Some((0,1,0).into()..=(3,29,11).into()));
+        assert_eq!(iter.next(), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use thiserror::Error;
+use crate::warehouse::{ModificationError, Product, Warehouse, WarehouseChange};
+
+/// Bounded undo/redo stacks for an interactive session
+/// Each entry holds every change made by one command, undone and redone as a unit
+pub struct History<I> {
+    undo: VecDeque<Entry<I>>,
+    redo: Vec<Entry<I>>,
+    limit: usize,
+}
+
+// The identifiers picked by the command are kept too, so undoing it takes the picks back
+struct Entry<I> {
+    changes: Vec<WarehouseChange<I>>,
+    picks: Vec<i64>,
+}
+
+impl<I: Product> History<I> {
+    pub fn new(limit: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            limit,
+        }
+    }
+
+    /// Runs a command as a transaction, remembering its changes so it can be undone
+    pub fn run<T, E>(&mut self, warehouse: &mut Warehouse<I>, f: impl FnOnce(&mut Warehouse<I>) -> Result<T, E>) -> Result<T, E> {
+        let (value, changes, picks) = warehouse.transaction_with_changes(f)?;
+        if !changes.is_empty() {
+            self.push_undo(Entry { changes, picks });
+            self.redo.clear();
+        }
+        Ok(value)
+    }
+
+    pub fn undo(&mut self, warehouse: &mut Warehouse<I>) -> Result<usize, HistoryError> {
+        let entry = self.undo.pop_back().ok_or(HistoryError::NothingToUndo)?;
+        let inverse: Vec<_> = entry.changes.iter().rev().map(WarehouseChange::inverse).collect();
+
+        match Self::apply_all(warehouse, &inverse) {
+            Ok(()) => {
+                warehouse.uncount_picks(&entry.picks);
+                let count = entry.changes.len();
+                self.redo.push(entry);
+                Ok(count)
+            }
+            Err(e) => {
+                self.undo.push_back(entry);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn redo(&mut self, warehouse: &mut Warehouse<I>) -> Result<usize, HistoryError> {
+        let entry = self.redo.pop().ok_or(HistoryError::NothingToRedo)?;
+
+        match Self::apply_all(warehouse, &entry.changes) {
+            Ok(()) => {
+                warehouse.count_picks(&entry.picks);
+                let count = entry.changes.len();
+                self.push_undo(entry);
+                Ok(count)
+            }
+            Err(e) => {
+                self.redo.push(entry);
+                Err(e)
+            }
+        }
+    }
+
+    /// Must be called whenever the warehouse is replaced, as the history no longer applies to it
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    fn push_undo(&mut self, entry: Entry<I>) {
+        if self.undo.len() == self.limit {
+            self.undo.pop_front();
+        }
+        if self.limit > 0 {
+            self.undo.push_back(entry);
+        }
+    }
+
+    fn apply_all(warehouse: &mut Warehouse<I>, changes: &[WarehouseChange<I>]) -> Result<(), HistoryError> {
+        warehouse.transaction(|tx| {
+            changes.iter().try_for_each(|change| tx.apply_change(change))
+        }).map_err(HistoryError::Conflict)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("Nothing to undo")]
+    NothingToUndo,
+    #[error("Nothing to redo")]
+    NothingToRedo,
+    #[error("The warehouse no longer matches the history: {0}")]
+    Conflict(ModificationError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::{StoreCoords, WarehouseDimensions};
+    use crate::warehouse::{ProductCategory, WarehouseEntry};
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    #[test]
+    fn test_undo_remove_restores_exact_product() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut history = History::new(10);
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+
+        let product = AnyOldProduct::new(1, "Beam".to_string(), 3, ProductCategory::Oversized { zone_count: 2 });
+        let timestamp = product.timestamp();
+        assert!(history.run(&mut warehouse, |w| w.add_product(product, &mut allocator)).is_ok());
+        assert!(history.run(&mut warehouse, |w| w.move_product((0,0,0).into(), (1,1,1).into())).is_ok());
+        assert!(history.run(&mut warehouse, |w| w.remove_product((1,1,1).into())).is_ok());
+        assert_eq!(warehouse.search_by_id(&1), None);
+
+        assert_eq!(history.undo(&mut warehouse).ok(), Some(1));
+        let restored = warehouse.get_product_ref(&(1,1,1).into()).unwrap().expect_ref("Removal was undone");
+        assert_eq!(restored.timestamp(), timestamp);
+        assert_eq!(restored.amount(), 3);
+        assert!(matches!(warehouse.get_product_ref(&(1,1,2).into()), Ok(WarehouseEntry::OversizedPlaceholder)));
+        assert!(matches!(warehouse.get_product_ref(&(1,1,3).into()), Ok(WarehouseEntry::OversizedPlaceholder)));
+
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![StoreCoords(0,0,0)]));
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert_eq!(warehouse.search_by_id(&1), None);
+        assert!(matches!(history.undo(&mut warehouse), Err(HistoryError::NothingToUndo)));
+
+        assert!(history.redo(&mut warehouse).is_ok());
+        assert!(history.redo(&mut warehouse).is_ok());
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![StoreCoords(1,1,1)]));
+    }
+
+    #[test]
+    fn test_undo_keeps_search_order() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut history = History::new(10);
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for amount in 1..=3 {
+            let product = AnyOldProduct::new(1, "Bolts".to_string(), amount, ProductCategory::Normal);
+            assert!(history.run(&mut warehouse, |w| w.add_product(product, &mut allocator)).is_ok());
+        }
+        let before = warehouse.search_by_id(&1).cloned();
+        assert_eq!(before, Some(vec![StoreCoords(0,0,0), StoreCoords(0,0,1), StoreCoords(0,0,2)]));
+
+        assert!(history.run(&mut warehouse, |w| w.remove_product((0,0,0).into())).is_ok());
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert_eq!(warehouse.search_by_id(&1).cloned(), before);
+        assert_eq!(warehouse.search_by_name("Bolts").cloned(), before);
+
+        assert!(history.run(&mut warehouse, |w| w.move_product((0,0,1).into(), (1,1,1).into())).is_ok());
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![StoreCoords(0,0,0), StoreCoords(0,0,2), StoreCoords(1,1,1)]));
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert_eq!(warehouse.search_by_id(&1).cloned(), before);
+        assert_eq!(warehouse.search_by_name("Bolts").cloned(), before);
+        assert!(warehouse.verify().is_ok());
+    }
+
+    #[test]
+    fn test_new_command_clears_redo_and_limit() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut history = History::new(2);
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+
+        for identifier in 0..3 {
+            let product = AnyOldProduct::new(identifier, format!("Item {}", identifier), 5, ProductCategory::Normal);
+            assert!(history.run(&mut warehouse, |w| w.add_product(product, &mut allocator)).is_ok());
+        }
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert!(matches!(history.undo(&mut warehouse), Err(HistoryError::NothingToUndo)));
+        assert_eq!(warehouse.search_by_id(&0).map(Vec::len), Some(1));
+
+        assert!(history.run(&mut warehouse, |w| w.pick(&(0,0,0).into(), 2)).is_ok());
+        assert!(matches!(history.redo(&mut warehouse), Err(HistoryError::NothingToRedo)));
+        assert!(history.undo(&mut warehouse).is_ok());
+        assert_eq!(warehouse.quantity_by_id(&0), Some(5));
+    }
+}
@@ -0,0 +1,204 @@
+use std::io::{BufRead, Write};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use time::UtcDateTime;
+use crate::coords::{StoreCoords, WarehouseDimensions};
+use crate::warehouse::{ModificationError, Product, Warehouse, WarehouseChange, WarehouseEntry};
+
+/// A committed change, as written to the journal
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEvent<I> {
+    pub timestamp: UtcDateTime,
+    pub operator: Option<String>,
+    pub change: WarehouseChange<I>,
+}
+
+pub trait JournalSink<I> {
+    /// Called once for every committed change, in order
+    fn append(&mut self, event: &JournalEvent<I>) -> std::io::Result<()>;
+}
+
+/// Writes one JSON event per line, flushing after each one
+pub struct JsonlJournal<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonlJournal<W> {
+    pub fn new(writer: W) -> Self {
+        JsonlJournal { writer }
+    }
+}
+
+impl<I: Product, W: Write> JournalSink<I> for JsonlJournal<W> {
+    fn append(&mut self, event: &JournalEvent<I>) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+}
+
+pub fn read_jsonl<I: Product>(reader: impl BufRead) -> Result<Vec<JournalEvent<I>>, serde_json::Error> {
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(serde_json::Error::io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line)?);
+    }
+    Ok(events)
+}
+
+#[derive(Debug, Error)]
+#[error("Journal event {index} could not be applied: {source}")]
+pub struct ReplayError {
+    pub index: usize,
+    #[source]
+    pub source: ModificationError,
+}
+
+impl<I: Product> Warehouse<I> {
+    /// Applies journal events on top of the current state
+    /// Rebuilding an identical warehouse requires starting from the state the journal was started on
+    /// Replayed changes are sent to this warehouse's own journal, if one is attached
+    pub fn replay<'a>(&mut self, events: impl IntoIterator<Item = &'a JournalEvent<I>>) -> Result<(), ReplayError>
+    where
+        I: 'a,
+    {
+        for (index, event) in events.into_iter().enumerate() {
+            self.apply_change(&event.change).map_err(|source| ReplayError { index, source })?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the warehouse as it was at the given instant, from a journal started on an empty warehouse
+pub fn state_at<I: Product>(events: &[JournalEvent<I>], dimensions: WarehouseDimensions, at: UtcDateTime) -> Result<Warehouse<I>, ReplayError> {
+    let mut warehouse = Warehouse::new(dimensions);
+    warehouse.replay(events.iter().take_while(|event| event.timestamp <= at))?;
+    Ok(warehouse)
+}
+
+/// Product occupying a zone at the given instant, following Oversized placeholders to their product
+pub fn occupant_at<I: Product>(events: &[JournalEvent<I>], dimensions: WarehouseDimensions, coords: &StoreCoords, at: UtcDateTime) -> Result<Option<I>, ReplayError> {
+    if !dimensions.contains(coords) {
+        return Err(ReplayError { index: 0, source: ModificationError::InvalidCoords });
+    }
+
+    let warehouse = state_at(events, dimensions, at)?;
+    let owner = warehouse.find_owner(coords);
+    Ok(owner.and_then(|owner| match warehouse.get_product_ref(&owner) {
+        Ok(WarehouseEntry::Some(product)) => Some(product.clone()),
+        _ => None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+    use crate::warehouse::ProductCategory;
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    // State of the warehouse, without the snapshot envelope and its creation time
+    fn snapshot(warehouse: &Warehouse<AnyOldProduct>) -> String {
+        serde_json::to_string_pretty(warehouse).unwrap()
+    }
+
+    // Named after the process, so test runs sharing the temp directory keep their own journals
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("warehouse_journal_{}_{}.jsonl", std::process::id(), name))
+    }
+
+    fn journaled_warehouse(path: &std::path::Path) -> Warehouse<AnyOldProduct> {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let file = std::fs::File::create(path).unwrap();
+        warehouse.attach_journal(Box::new(JsonlJournal::new(file)));
+        warehouse.set_operator(Some("Ana".to_string()));
+
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for (identifier, name, quality) in [
+            (1, "Bolts", ProductCategory::Normal),
+            (2, "Beam", ProductCategory::Oversized { zone_count: 2 }),
+            (3, "Nuts", ProductCategory::Normal),
+        ] {
+            let product = AnyOldProduct::new(identifier, name.to_string(), 10, quality);
+            assert!(warehouse.add_product(product, &mut allocator).is_ok());
+        }
+        assert!(warehouse.remove_product((0,0,0).into()).is_ok());
+        assert!(warehouse.move_product((0,1,0).into(), (1,0,0).into()).is_ok());
+        assert!(warehouse.pick(&(1,0,0).into(), 4).is_ok());
+        assert!(warehouse.split_stack(&(1,0,0).into(), 2, &mut allocator).is_ok());
+
+        // Rolled back changes never reach the journal
+        let result = warehouse.transaction(|tx| {
+            tx.remove_product((1,0,0).into())?;
+            tx.remove_product((1,1,1).into())
+        });
+        assert!(result.is_err());
+        warehouse
+    }
+
+    #[test]
+    fn test_replay_rebuilds_warehouse() {
+        let path = temp_path("replay");
+        let warehouse = journaled_warehouse(&path);
+
+        let events: Vec<JournalEvent<AnyOldProduct>> = read_jsonl(std::io::BufReader::new(std::fs::File::open(&path).unwrap())).unwrap();
+        assert_eq!(events.len(), 8);
+        assert!(events.iter().all(|event| event.operator.as_deref() == Some("Ana")));
+
+        let mut rebuilt = Warehouse::new(warehouse.dimensions());
+        assert!(rebuilt.replay(&events).is_ok());
+        assert_eq!(snapshot(&rebuilt), snapshot(&warehouse));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replacing_warehouse_keeps_journaling() {
+        let path = temp_path("hand_over");
+        let mut warehouse = journaled_warehouse(&path);
+        let read = || read_jsonl::<AnyOldProduct>(std::io::BufReader::new(std::fs::File::open(&path).unwrap())).unwrap();
+
+        let mut rebuilt = Warehouse::new(warehouse.dimensions());
+        assert!(rebuilt.replay(&read()).is_ok());
+        warehouse.hand_over_journal(&mut rebuilt);
+        assert_eq!(read().len(), 8);
+
+        let product = AnyOldProduct::new(4, "Pins".to_string(), 1, ProductCategory::Normal);
+        assert!(rebuilt.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+        assert!(warehouse.remove_product((1,0,0).into()).is_ok());
+        let events = read();
+        assert_eq!(events.len(), 9);
+        assert!(matches!(&events[8].change, WarehouseChange::Added { product, .. } if *product.identifier() == 4));
+        assert_eq!(events[8].operator.as_deref(), Some("Ana"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_occupant_at() {
+        let path = temp_path("occupant");
+        journaled_warehouse(&path);
+
+        let mut events: Vec<JournalEvent<AnyOldProduct>> = read_jsonl(std::io::BufReader::new(std::fs::File::open(&path).unwrap())).unwrap();
+        // Spread the events one day apart, starting on 2026-09-01
+        let start = UtcDateTime::new(time::Date::from_calendar_date(2026, time::Month::September, 1).unwrap(), time::Time::MIDNIGHT);
+        for (day, event) in events.iter_mut().enumerate() {
+            event.timestamp = start + Duration::days(day as i64);
+        }
+        let dimensions = WarehouseDimensions::new(2, 2, 4);
+
+        let at = |day: i64| start + Duration::days(day) + Duration::hours(12);
+        let name_at = |coords: StoreCoords, day: i64| {
+            occupant_at(&events, dimensions, &coords, at(day)).unwrap().map(|p| p.name().clone())
+        };
+        assert_eq!(name_at((0,0,0).into(), 0).as_deref(), Some("Bolts"));
+        assert_eq!(name_at((0,0,0).into(), 3), None);
+        assert_eq!(name_at((0,0,3).into(), 2).as_deref(), Some("Beam"));
+        assert_eq!(name_at((0,1,0).into(), 2).as_deref(), Some("Nuts"));
+        assert_eq!(name_at((0,1,0).into(), 4), None);
+        assert_eq!(occupant_at(&events, dimensions, &(1,0,0).into(), at(5)).unwrap().map(|p| p.amount()), Some(6));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,18 +1,31 @@
 use std::cmp::Ordering;
+use std::convert::Infallible;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::process::ExitCode;
 use std::str::FromStr;
 use serde_derive::{Deserialize, Serialize};
 use time::{Duration, UtcDateTime};
-use coords::StoreCoords;
-use warehouse::{Product, ProductCategory, Warehouse, WarehouseAllocator, WarehouseEntry};
+use allocators::AnyAllocator;
+use coords::{StoreCoords, WarehouseDimensions};
+use filters::FilterRule;
+use history::History;
+use journal::JsonlJournal;
+use warehouse::{AbcThresholds, ExpiryHorizons, PickMode, PickTarget, Product, ProductCategory, SnapshotError, SweepAction, VelocityClass, Warehouse, WarehouseEntry};
 
 mod warehouse;
+mod allocators;
 mod free_map;
 mod coords;
-
-#[derive(Serialize,Deserialize)]
+mod filters;
+mod journal;
+mod history;
+mod cli;
+mod script;
+mod csv;
+
+#[derive(Clone, Serialize, Deserialize)]
 struct AnyOldProduct {
     identifier: i64,
     name: String,
@@ -68,6 +81,8 @@ impl Product for AnyOldProduct {
     fn timestamp(&self) -> time::UtcDateTime { self.timestamp }
 
     fn set_timestamp(&mut self, timestamp: time::UtcDateTime) { self.timestamp = timestamp; }
+
+    fn set_amount(&mut self, amount: u64) { self.amount = amount; }
 }
 
 impl Display for AnyOldProduct {
@@ -87,245 +102,36 @@ impl AnyOldProduct {
     }
 }
 
-struct WarehouseAllocatorClosestFirst;
-struct WarehouseAllocatorClosestFirstEfficient;
-
-impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorClosestFirst {
-    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
-        let mut oversized_count = if let ProductCategory::Oversized { zone_count } = product.quality() {
-            *zone_count
-        } else { 0 };
-        let max_row = if let ProductCategory::Fragile { max_row, .. } = product.quality() {
-            Some(*max_row)
-        } else { None };
-        
-        for (i,row) in warehouse.store().iter().enumerate() {
-            if let Some(max_row) = max_row {
-                if i > max_row {
-                    break;
-                }
-            }
-            for (j, shelf) in row.iter().enumerate() {
-                let oversized_count_store = oversized_count;
-                for (k, zone) in shelf.iter().enumerate() {
-                    match zone {
-                        WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {}
-                        WarehouseEntry::None => {
-                            if oversized_count == 0 {
-                                return Some((i, j, k - oversized_count_store).into())
-                            }
-                            oversized_count -= 1;
-                        }
-                    }
-                }
-                if oversized_count_store != oversized_count {
-                    oversized_count = oversized_count_store;
-                }
-            }
-        }
-
-        None
-    }
+// Empty warehouse with the default size and no admission filters
+fn new_warehouse() -> Warehouse<AnyOldProduct> {
+    Warehouse::new(WarehouseDimensions::new(20, 20, 20))
 }
 
-impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorClosestFirstEfficient {
-    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
-        while let Some(range) = warehouse.free_map().iter().next() {
-            if let ProductCategory::Fragile { max_row, .. } = product.quality() {
-                if range.start().0 > *max_row {
-                    continue
-                }
-            }
-            
-            if let ProductCategory::Oversized { zone_count } = product.quality() {
-                let mut try_end = range.start().clone();
-                if try_end.2 + zone_count >= warehouse.store_max_idx() - 1 {
-                    return None
-                }
-                try_end.2 += zone_count;
-                if !range.contains(&try_end) {
-                    return None
-                }
-            }
-            
-            return Some(range.start().clone())
-        }
-        None
-    }
-}
-
-struct WarehouseAllocatorRoundRobin {
-    last_coords: StoreCoords,
-}
-
-impl WarehouseAllocatorRoundRobin {
-    fn new() -> Self {
-        WarehouseAllocatorRoundRobin {
-            last_coords: (0,0,0).into()
-        }
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
     }
-}
-
-impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorRoundRobin {
-    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
-        let mut oversized_count = if let ProductCategory::Oversized { zone_count } = product.quality() {
-            *zone_count
-        } else { 0 };
-        let max_row = if let ProductCategory::Fragile { max_row, .. } = product.quality() {
-            Some(*max_row)
-        } else { None };
-        let (mut i, mut j, mut k) = (&self.last_coords).into();
-        
-        while i < warehouse.store().len() {
-            if let Some(max_row) = max_row {
-                if i > max_row {
-                    break;
-                }
-            }
-            let row = &warehouse.store()[i];
-            while j < row.len() {
-                let shelf = &row[j];
-                let oversized_count_store = oversized_count;
-                while k < shelf.len() {
-                    let zone = &shelf[k];
-                    
-                    match zone {
-                        WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {}
-                        WarehouseEntry::None => {
-                            if oversized_count == 0 {
-                                let coords: StoreCoords = (i, j, k).into();
-                                self.last_coords = coords.clone();
-                                return Some(coords);
-                            }
-                            oversized_count -= 1;
-                        }
-                    }
-                    
-                    k += 1;
-                }
-                
-                j += 1;
-                k = 0;
-                if oversized_count_store != oversized_count {
-                    oversized_count = oversized_count_store;
-                }
-            }
-            
-            i += 1;
-            j = 0;
-        }
-
-        None
-    }
-}
-
-struct WarehouseAllocatorRoundRobinEfficient {
-    last_coords: StoreCoords,
-}
-
-impl WarehouseAllocatorRoundRobinEfficient {
-    fn new() -> Self {
-        WarehouseAllocatorRoundRobinEfficient {
-            last_coords: (0,0,0).into()
-        }
-    }
-}
-
-impl<I: Product> WarehouseAllocator<I> for WarehouseAllocatorRoundRobinEfficient {
-    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords> {
-        let next_coords = self.last_coords.next(warehouse.store_max_idx())?;
-        let iter = warehouse.free_map().iter_from(next_coords);
-        for range in iter {
-            // We don't care about partial overlaps here, since last_coords should always be pointing at the last allocation
-            if let ProductCategory::Fragile { max_row, .. } = product.quality() {
-                if range.start().0 > *max_row {
-                    continue
-                }
-            }
-
-            if let ProductCategory::Oversized { zone_count } = product.quality() {
-                let mut try_end = range.start().clone();
-                if try_end.2 + zone_count >= warehouse.store_max_idx() - 1 {
-                    return None
-                }
-                try_end.2 += zone_count;
-                if !range.contains(&try_end) {
-                    return None
-                }
-            }
-
-            return Some(range.start().clone())
-        }
-
-        None
+    // Commands piped in are run as a script instead of answering the menu prompts
+    if !std::io::stdin().is_terminal() {
+        return cli::run(&["script".to_string()]);
     }
-}
-
-fn main() {
-    let mut warehouse = Warehouse::new(20);
-    //let mut warehouse_allocator = WarehouseAllocatorClosestFirst;
-    let mut warehouse_allocator = WarehouseAllocatorClosestFirstEfficient;
-    //let mut warehouse_allocator = WarehouseAllocatorRoundRobin::new();
-    //let mut warehouse_allocator = WarehouseAllocatorRoundRobinEfficient::new();
     
-    // Filter setup goes here
+    let mut warehouse = new_warehouse();
+    let mut history = History::new(50);
+    // Chosen with the allocator command, and saved with the warehouse when it is exported
+    let mut warehouse_allocator = AnyAllocator::default();
     
     println!("The grocery store is open.");
     loop {
         print_command_list();
-        let command = read_valid_stdin("Command: ", maplidator_int_index_limit(11));
+        let command = read_valid_stdin("Command: ", maplidator_int_index_limit(23));
         
         match command {
             1 => { // Add product 
-                let identifier = read_valid_stdin("Product identifier: ", |input| {
-                    let input = input.trim();
-                    input.parse().map_err(|_| "Failed to parse into number")
-                });
-                let name = read_valid_stdin("Product name: ", maplidator_identity_trim);
-                let amount: u64 = read_valid_stdin("Amount in stack: ", |input| {
-                    let input = input.trim();
-                    input.parse().map_err(|_| "Failed to parse into number")
-                });
-
-                let quality = read_valid_stdin(
-                    "Select a category:\n1) Fragile\n2) Oversized\n3)Normal\nYour choice: ",
-                |input| {
-                        let input = maplidator_int_index_limit(3)(input)?;
-                        match input {
-                            1 => {
-                                let expiry_date = read_valid_stdin("Expiry date (YYYY-MM-DD): ", |input| {
-                                    let mut input = input.trim().split('-');
-                                    let year= get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid year")?;
-                                    let month: u8 = get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid month")?;
-                                    let month = month.try_into().map_err(|_| "Invalid month")?;
-                                    let day = get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid day")?;
-
-                                    if input.next().is_some() {
-                                        return Err("Extra data found during parsing");
-                                    }
-
-                                    time::Date::from_calendar_date(year, month, day).map_err(|_| "Invalid date")
-                                });
-                                let max_row = read_valid_stdin("Max row: ", maplidator_int_index_limit(warehouse.store_max_idx()));
-                                
-                                Ok(ProductCategory::Fragile { expiry_date, max_row })
-                            }
-                            2 => {
-                                let zone_count = read_valid_stdin("Zones occupied: ", maplidator_int_index_limit(warehouse.store_max_idx()));
-                                
-                                Ok(ProductCategory::Oversized { zone_count })
-                            }
-                            3 => {
-                                Ok(ProductCategory::Normal)
-                            }
-                            _ => unreachable!()
-                        }
-                    }
-                );
+                let product = read_product_stdin(warehouse.dimensions());
                 
-                let product = AnyOldProduct::new(identifier, name, amount, quality);
-                
-                match warehouse.add_product(product, &mut warehouse_allocator) {
+                match history.run(&mut warehouse, |w| w.add_product(product, &mut warehouse_allocator)) {
                     Ok(()) => println!("Product added"),
                     Err(e) => println!("Failed to add product: {}", e),
                 }
@@ -340,11 +146,14 @@ fn main() {
                     }
                 };
                 
-                if let WarehouseEntry::Some(product) = warehouse.get_product_ref(&coords) {
+                if let Ok(WarehouseEntry::Some(product)) = warehouse.get_product_ref(&coords) {
                     println!("Deleting item at row {}, shelf {}, zone {}:\n{}\n", coords.0+1, coords.1+1, coords.2+1, product);
                     let confirm = read_valid_stdin("Confirm delete [y/n]: ", maplidator_yes_or_no);
                     if confirm {
-                        warehouse.remove_product(coords).expect("Should have already checked product exists");
+                        match history.run(&mut warehouse, |w| w.remove_product(coords)) {
+                            Ok(()) => println!("Product removed"),
+                            Err(e) => println!("Failed to remove product: {}", e),
+                        }
                     } else {
                         println!("Cancelled. No changes were made.");
                     }
@@ -357,9 +166,9 @@ fn main() {
                 println!("There are {} products in the warehouse", keys.len());
                 
                 for (_,val) in keys {
-                    let val = warehouse.get_product_ref(&val[0])
-                        .expect_ref("Only Some values in map");
-                    println!("{}", val)
+                    if let Ok(WarehouseEntry::Some(product)) = warehouse.get_product_ref(&val[0]) {
+                        println!("{}", product)
+                    }
                 }
             }
             4 => { // Search ID
@@ -372,6 +181,7 @@ fn main() {
                     None => println!("Not found"),
                     Some(val) => {
                         println!("We have {} items with identifier {}", val.len(), identifier);
+                        println!("{} units in total", warehouse.quantity_by_id(&identifier).unwrap_or(0));
                     }
                 }
             }
@@ -411,55 +221,544 @@ fn main() {
                 };
                 
                 let product = match warehouse.get_product_ref(&coords) {
-                    WarehouseEntry::None | WarehouseEntry::OversizedPlaceholder => {
+                    Ok(WarehouseEntry::Some(x)) => {x}
+                    Ok(WarehouseEntry::None | WarehouseEntry::OversizedPlaceholder) => {
                         println!("No details available.");
                         continue
                     }
-                    WarehouseEntry::Some(x) => {x}
+                    Err(e) => {
+                        println!("{}", e);
+                        continue
+                    }
                 };
                 
                 println!("Product detail:\n{}", product);
             }
-            8 => { // Search Expiry Dates
-                let date = read_valid_stdin("Date to search (YYYY-MM-DD): ", |input| {
-                    let mut input = input.trim().split('-');
-                    let year= get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid year")?;
-                    let month: u8 = get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid month")?;
-                    let month = month.try_into().map_err(|_| "Invalid month")?;
-                    let day = get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid day")?;
-
-                    if input.next().is_some() {
-                        return Err("Extra data found during parsing");
-                    }
-
-                    time::Date::from_calendar_date(year, month, day).map_err(|_| "Invalid date")
+            8 => { // Expiry report
+                let date = read_valid_stdin("Report as of (YYYY-MM-DD): ", maplidator_date);
+                let horizons = read_valid_stdin("Bucket limits in days, blank for 3,7,30: ", |input| match input.trim() {
+                    "" => Ok(ExpiryHorizons::default()),
+                    input => input.parse(),
                 });
                 
-                println!("{} items have expired", warehouse.search_expiry_dates(..date).count());
-                println!("{} items will expire within 3 days", warehouse.search_expiry_dates(date..=date+Duration::days(3)).count());
+                let report = warehouse.expiry_report(date, &horizons);
+                for bucket in &report.buckets {
+                    println!("{}: {} stacks, {} units", bucket.label, bucket.stacks().count(), bucket.total_amount());
+                    for (row, stacks) in &bucket.rows {
+                        println!("\tRow {}", row);
+                        for stack in stacks {
+                            println!("\t\t{:?} {} x{}, expires {}", stack.coords, stack.name, stack.amount, stack.expiry_date);
+                        }
+                    }
+                }
+                
+                let filename = read_valid_stdin("Export to (.json or .csv), blank to skip: ", maplidator_identity_trim);
+                if filename.is_empty() {
+                    continue
+                }
+                let csv = is_csv(&filename);
+                let result = File::create(filename).and_then(|file| {
+                    let mut writer = BufWriter::new(file);
+                    if csv {
+                        csv::export_expiry_csv(&report, &mut writer)?;
+                    } else {
+                        serde_json::to_writer_pretty(&mut writer, &report)?;
+                    }
+                    writer.flush()
+                });
+                match result {
+                    Ok(()) => println!("Done"),
+                    Err(e) => println!("Failed to export: {}", e),
+                }
             }
             9 => { break }
             10 => { // import
-                let filename = read_valid_stdin("File to read: ", maplidator_identity_trim);
-                let file = File::open(filename);
-                let mut reader = BufReader::new(file.unwrap());
+                let filename = read_valid_stdin("File to read (.json, .csv or .bin): ", maplidator_identity_trim);
+                if is_csv(&filename) {
+                    // CSV rows are added to the current store, as a single undoable step
+                    let result = File::open(&filename)
+                        .map_err(csv::CsvError::from)
+                        .and_then(|mut file| history.run(&mut warehouse, |w| csv::import_csv(w, &mut file, &mut warehouse_allocator)));
+                    match result {
+                        Ok(report) => {
+                            for row in &report.errors {
+                                println!("Line {}: {}", row.line, row.error);
+                            }
+                            println!("{} products imported, {} rows rejected", report.imported.len(), report.errors.len());
+                        }
+                        Err(e) => println!("Failed to import: {}\nNo changes were made.", e),
+                    }
+                    continue
+                }
                 
-                warehouse = Warehouse::from_json(&mut reader).unwrap();
+                let binary = is_binary(&filename);
+                let loaded = File::open(filename)
+                    .map_err(SnapshotError::from)
+                    .and_then(|file| if binary {
+                        // Indices are rebuilt from the products, so there is nothing to repair
+                        Warehouse::from_binary(&mut BufReader::new(file)).map(|w| (w, Default::default()))
+                    } else {
+                        Warehouse::from_json(&mut BufReader::new(file))
+                    });
+                let (mut loaded, report) = match loaded {
+                    Ok(x) => x,
+                    Err(e) => {
+                        println!("Failed to import: {}\nNo changes were made.", e);
+                        continue
+                    }
+                };
+                warehouse.hand_over_journal(&mut loaded);
+                warehouse = loaded;
+                history.clear();
+                if let Some(allocator) = warehouse.allocator() {
+                    warehouse_allocator = allocator.clone();
+                    println!("Using the saved allocator: {}", warehouse_allocator);
+                }
+                for issue in &report.issues {
+                    println!("Repaired: {}", issue);
+                }
                 println!("Done")
             }
             11 => { // export
-                let filename = read_valid_stdin("File to write: ", maplidator_identity_trim);
-                let file = File::create(filename);
-                let mut writer = BufWriter::new(file.unwrap());
+                let filename = read_valid_stdin("File to write (.json, .csv or .bin): ", maplidator_identity_trim);
+                let csv = is_csv(&filename);
+                let compress = is_binary(&filename) && read_valid_stdin("Compress [y/n]: ", maplidator_yes_or_no);
+                warehouse.set_allocator(Some(warehouse_allocator.clone()));
+                let result = File::create(&filename)
+                    .map_err(SnapshotError::from)
+                    .and_then(|file| {
+                        let mut writer = BufWriter::new(file);
+                        if csv {
+                            csv::export_csv(&warehouse, &mut writer)?;
+                        } else if is_binary(&filename) {
+                            warehouse.to_binary(&mut writer, compress)?;
+                        } else {
+                            warehouse.to_json(&mut writer)?;
+                        }
+                        writer.flush().map_err(SnapshotError::from)
+                    });
+                match result {
+                    Ok(()) => println!("Done"),
+                    Err(e) => println!("Failed to export: {}", e),
+                }
+            }
+            12 => { // Manage filters
+                println!("There are {} admission filters active", warehouse.list_filters().len());
+                for filter in warehouse.list_filters() {
+                    println!("\t{}: {}", filter.name(), filter.description());
+                }
                 
-                warehouse.to_json(&mut writer);
-                println!("Done")
+                let action = read_valid_stdin("1) Add filter\n2) Remove filter\n3) Load filters from config file\n4) Back\nYour choice: ", maplidator_int_index_limit(4));
+                match action {
+                    1 => {
+                        let rule = match read_valid_stdin(
+                            "Select a filter:\n1) Reject expired\n2) Max stacks per identifier\n3) Min shelf life\n4) Unique names\nYour choice: ",
+                            maplidator_int_index_limit(4)
+                        ) {
+                            1 => FilterRule::RejectExpired,
+                            2 => {
+                                let max_stacks = read_valid_stdin("Max stacks: ", |input| {
+                                    let input = input.trim();
+                                    input.parse().map_err(|_| "Failed to parse into number")
+                                });
+                                FilterRule::MaxStacksPerIdentifier { max_stacks }
+                            }
+                            3 => {
+                                let days = read_valid_stdin("Min days of shelf life: ", |input| {
+                                    let input = input.trim();
+                                    input.parse().map_err(|_| "Failed to parse into number")
+                                });
+                                FilterRule::MinShelfLife { days }
+                            }
+                            4 => FilterRule::UniqueNames,
+                            _ => unreachable!()
+                        };
+                        
+                        match warehouse.add_rule(rule) {
+                            Ok(()) => println!("Filter added"),
+                            Err(e) => println!("Failed to add filter: {}", e),
+                        }
+                    }
+                    2 => {
+                        let name = read_valid_stdin("Filter name: ", maplidator_identity_trim);
+                        match warehouse.remove_filter(&name) {
+                            Some(_) => println!("Filter removed"),
+                            None => println!("Not found"),
+                        }
+                    }
+                    3 => {
+                        let filename = read_valid_stdin("File to read: ", maplidator_identity_trim);
+                        let rules = File::open(filename)
+                            .map_err(|e| e.to_string())
+                            .and_then(|file| FilterRule::load_config(&mut BufReader::new(file)).map_err(|e| e.to_string()));
+                        
+                        match rules {
+                            Ok(rules) => {
+                                for rule in rules {
+                                    if let Err(e) = warehouse.add_rule(rule) {
+                                        println!("Skipped filter: {}", e);
+                                    }
+                                }
+                                println!("Done")
+                            }
+                            Err(e) => println!("Failed to load config: {}", e),
+                        }
+                    }
+                    4 => {}
+                    _ => unreachable!()
+                }
+            }
+            13 => { // Move product
+                println!("Browse the store and select an item to move");
+                let from = match warehouse.store_browse() {
+                    Ok(x) => {x}
+                    Err(_) => {
+                        println!("Browse cancelled. No changes were made.");
+                        continue
+                    }
+                };
+                
+                if !matches!(warehouse.get_product_ref(&from), Ok(WarehouseEntry::Some(_))) {
+                    println!("Cannot move empty product\nCancelled");
+                    continue
+                }
+                
+                let to: StoreCoords = read_valid_stdin("Destination (row shelf zone): ", |input| {
+                    input.parse().map_err(|_| "Expected three numbers separated by spaces")
+                });
+                
+                match history.run(&mut warehouse, |w| w.move_product(from, to)) {
+                    Ok(()) => println!("Product moved"),
+                    Err(e) => println!("Failed to move product: {}", e),
+                }
+            }
+            14 => { // Adjust stack
+                println!("Browse the store and select a stack");
+                let coords = match warehouse.store_browse() {
+                    Ok(x) => {x}
+                    Err(_) => {
+                        println!("Browse cancelled. No changes were made.");
+                        continue
+                    }
+                };
+                
+                if let Ok(WarehouseEntry::Some(product)) = warehouse.get_product_ref(&coords) {
+                    println!("{} has {} units in this stack", product.name(), product.amount());
+                } else {
+                    println!("Cannot adjust empty product\nCancelled");
+                    continue
+                }
+                
+                let action = read_valid_stdin("1) Pick\n2) Restock\n3) Split\nYour choice: ", maplidator_int_index_limit(3));
+                let quantity: u64 = read_valid_stdin("Quantity: ", |input| {
+                    let input = input.trim();
+                    input.parse().map_err(|_| "Failed to parse into number")
+                });
+                
+                match action {
+                    1 => match history.run(&mut warehouse, |w| w.pick(&coords, quantity)) {
+                        Ok(0) => println!("Stack emptied and removed"),
+                        Ok(remaining) => println!("{} units left in stack", remaining),
+                        Err(e) => println!("Failed to pick: {}", e),
+                    }
+                    2 => match history.run(&mut warehouse, |w| w.restock(&coords, quantity)) {
+                        Ok(amount) => println!("{} units in stack", amount),
+                        Err(e) => println!("Failed to restock: {}", e),
+                    }
+                    3 => match history.run(&mut warehouse, |w| w.split_stack(&coords, quantity, &mut warehouse_allocator)) {
+                        Ok(new_coords) => println!("New stack at row {}, shelf {}, zone {}", new_coords.0, new_coords.1, new_coords.2),
+                        Err(e) => println!("Failed to split: {}", e),
+                    }
+                    _ => unreachable!()
+                }
+            }
+            15 => { // Receive batch
+                let count = read_valid_stdin("Number of products in batch: ", |input| {
+                    let input = input.trim();
+                    input.parse::<usize>().map_err(|_| "Failed to parse into number")
+                });
+                let products: Vec<_> = (0..count)
+                    .map(|i| {
+                        println!("Product {} of {}", i + 1, count);
+                        read_product_stdin(warehouse.dimensions())
+                    })
+                    .collect();
+                
+                let result = history.run(&mut warehouse, |tx| {
+                    for (i, product) in products.into_iter().enumerate() {
+                        tx.add_product(product, &mut warehouse_allocator).map_err(|e| (i, e))?;
+                    }
+                    Ok(())
+                });
+                
+                match result {
+                    Ok(()) => println!("Batch of {} products added", count),
+                    Err((i, e)) => println!("Failed to add product {}: {}\nNo changes were made.", i + 1, e),
+                }
+            }
+            16 => { // Audit journal
+                let action = read_valid_stdin(
+                    "1) Start journaling to file\n2) Stop journaling\n3) Rebuild warehouse from journal\n4) Look up a location on a past date\n5) Back\nYour choice: ",
+                    maplidator_int_index_limit(5)
+                );
+                match action {
+                    1 => {
+                        let filename = read_valid_stdin("File to append to: ", maplidator_identity_trim);
+                        let operator = read_valid_stdin("Operator name: ", maplidator_identity_trim);
+                        match File::options().create(true).append(true).open(filename) {
+                            Ok(file) => {
+                                warehouse.attach_journal(Box::new(JsonlJournal::new(BufWriter::new(file))));
+                                warehouse.set_operator(Some(operator));
+                                println!("Journaling started")
+                            }
+                            Err(e) => println!("Failed to open journal: {}", e),
+                        }
+                    }
+                    2 => {
+                        match warehouse.detach_journal() {
+                            Some(_) => println!("Journaling stopped"),
+                            None => println!("No journal active"),
+                        }
+                    }
+                    3 | 4 => {
+                        let filename = read_valid_stdin("Journal file to read: ", maplidator_identity_trim);
+                        let events = File::open(filename)
+                            .map_err(|e| e.to_string())
+                            .and_then(|file| journal::read_jsonl::<AnyOldProduct>(BufReader::new(file)).map_err(|e| e.to_string()));
+                        let events = match events {
+                            Ok(events) => events,
+                            Err(e) => {
+                                println!("Failed to read journal: {}", e);
+                                continue
+                            }
+                        };
+                        
+                        if action == 3 {
+                            let mut rebuilt = Warehouse::new(warehouse.dimensions());
+                            for rule in warehouse.list_filters().filter_map(|f| f.rule()) {
+                                rebuilt.add_rule(rule).expect("Rules of active filters are valid");
+                            }
+                            match rebuilt.replay(&events) {
+                                Ok(()) => {
+                                    // Only after the replay, which must not be journaled again
+                                    warehouse.hand_over_journal(&mut rebuilt);
+                                    warehouse = rebuilt;
+                                    history.clear();
+                                    println!("Replayed {} events", events.len())
+                                }
+                                Err(e) => println!("Failed to rebuild: {}\nNo changes were made.", e),
+                            }
+                        } else {
+                            let coords: StoreCoords = read_valid_stdin("Location (row shelf zone): ", |input| {
+                                input.parse().map_err(|_| "Expected three numbers separated by spaces")
+                            });
+                            let date = read_valid_stdin("Date (YYYY-MM-DD): ", maplidator_date);
+                            // Anything that happened during that day counts
+                            let at = UtcDateTime::new(date, time::Time::MAX);
+                            
+                            match journal::occupant_at(&events, warehouse.dimensions(), &coords, at) {
+                                Ok(Some(product)) => println!("At the end of {}:\n{}", date, product),
+                                Ok(None) => println!("Location was empty at the end of {}", date),
+                                Err(e) => println!("Failed to read journal: {}", e),
+                            }
+                        }
+                    }
+                    5 => {}
+                    _ => unreachable!()
+                }
+            }
+            17 => { // Undo
+                match history.undo(&mut warehouse) {
+                    Ok(count) => println!("Undid {} changes", count),
+                    Err(e) => println!("Failed to undo: {}", e),
+                }
+            }
+            18 => { // Redo
+                match history.redo(&mut warehouse) {
+                    Ok(count) => println!("Redid {} changes", count),
+                    Err(e) => println!("Failed to redo: {}", e),
+                }
+            }
+            19 => { // Sweep expired
+                let date = read_valid_stdin("Sweep products expired before (YYYY-MM-DD): ", maplidator_date);
+                let action = if read_valid_stdin("Move them to a quarantine area instead of removing [y/n]: ", maplidator_yes_or_no) {
+                    let parse = |input: String| input.parse::<StoreCoords>().map_err(|_| "Expected three numbers separated by spaces");
+                    let start = read_valid_stdin("Quarantine starts at (row shelf zone): ", parse);
+                    let end = read_valid_stdin("Quarantine ends at (row shelf zone): ", parse);
+                    SweepAction::Quarantine(start..=end)
+                } else {
+                    SweepAction::Remove
+                };
+                
+                let report = history.run(&mut warehouse, |w| Ok::<_, Infallible>(w.sweep_expired(date, &action)))
+                    .unwrap_or_else(|never| match never {});
+                for swept in &report.swept {
+                    let stack = &swept.stack;
+                    match &swept.quarantined_at {
+                        Some(to) => println!("Quarantined {} x{} (expired {}): {:?} -> {:?}", stack.name, stack.amount, stack.expiry_date, stack.coords, to),
+                        None => println!("Removed {} x{} (expired {}) from {:?}", stack.name, stack.amount, stack.expiry_date, stack.coords),
+                    }
+                }
+                if !report.is_ok() {
+                    println!("{} stacks could not be quarantined:", report.failed.len());
+                    for (stack, e) in &report.failed {
+                        println!("\t{} at {:?}: {}", stack.name, stack.coords, e);
+                    }
+                }
+                println!("{} expired stacks swept, {} units", report.swept.len(), report.total_amount());
+            }
+            20 => { // Pick by product
+                let target = read_valid_stdin("Product identifier or name: ", |input| Ok::<_, &str>(PickTarget::parse(&input)));
+                let quantity = read_valid_stdin("Quantity to pick: ", |input| {
+                    input.trim().parse::<u64>().map_err(|_| "Failed to parse into number")
+                });
+                let fifo = read_valid_stdin("1) Earliest expiry first\n2) Oldest stock first\nYour choice: ", maplidator_int_index_limit(2)) == 2;
+                let pick = |w: &mut Warehouse<AnyOldProduct>, mode| if fifo {
+                    w.pick_fifo(&target, quantity, mode)
+                } else {
+                    w.pick_fefo(&target, quantity, mode)
+                };
+                
+                let plan = match pick(&mut warehouse, PickMode::DryRun) {
+                    Ok(plan) => plan,
+                    Err(e) => {
+                        println!("Cannot pick {} units of {}: {}", quantity, target, e);
+                        continue
+                    }
+                };
+                println!("Pick list:");
+                for line in &plan {
+                    println!("\t{:?}: {} units", line.coords, line.quantity);
+                }
+                
+                if read_valid_stdin("Confirm pick [y/n]: ", maplidator_yes_or_no) {
+                    match history.run(&mut warehouse, |w| pick(w, PickMode::Commit)) {
+                        Ok(_) => println!("Picked {} units of {}", quantity, target),
+                        Err(e) => println!("Failed to pick: {}", e),
+                    }
+                }
+            }
+            21 => { // Oldest stock
+                let (days, min_age) = read_valid_stdin("Minimum days in the warehouse: ", |input| {
+                    let days = input.trim().parse::<i64>().map_err(|_| "Failed to parse into number")?;
+                    if days < 0 {
+                        return Err("Days cannot be negative");
+                    }
+                    days.checked_mul(86_400).map(|seconds| (days, Duration::seconds(seconds))).ok_or("Too many days")
+                });
+                
+                let stacks = warehouse.oldest_stock(UtcDateTime::now(), min_age);
+                println!("{} stacks stored for at least {} days", stacks.len(), days);
+                for stack in stacks {
+                    println!("\t{:?} {} x{} (ID {}), stored {} days ago", stack.coords, stack.name, stack.amount, stack.identifier, stack.age.whole_days());
+                }
+            }
+            22 => { // Re-slotting plan
+                let thresholds = read_valid_stdin("Share of picks for A and B classes, blank for 80,95: ", |input| match input.trim() {
+                    "" => Ok(AbcThresholds::default()),
+                    input => input.parse(),
+                });
+                
+                let classes = warehouse.velocity_classes(&thresholds);
+                for class in [VelocityClass::A, VelocityClass::B, VelocityClass::C] {
+                    println!("Class {}: {} products", class, classes.values().filter(|c| **c == class).count());
+                }
+                let plan = warehouse.reslotting_plan(&thresholds);
+                if plan.is_empty() {
+                    println!("The layout already matches the classes");
+                    continue
+                }
+                for step in &plan {
+                    println!("\t{} (ID {}, class {}): {:?} -> {:?}", step.name, step.identifier, step.class, step.from, step.to);
+                }
+                
+                if read_valid_stdin("Apply the moves [y/n]: ", maplidator_yes_or_no) {
+                    match history.run(&mut warehouse, |w| plan.iter().try_for_each(|step| w.move_product(step.from.clone(), step.to.clone()))) {
+                        Ok(()) => println!("{} stacks moved", plan.len()),
+                        Err(e) => println!("Failed to move the stacks: {}", e),
+                    }
+                }
+            }
+            23 => { // Choose allocator
+                println!("Current allocator: {}", warehouse_allocator);
+                for (index, entry) in allocators::REGISTRY.iter().enumerate() {
+                    println!("{}) {}: {}", index + 1, entry.name, entry.description);
+                }
+                let config = allocators::REGISTRY.len() + 1;
+                println!("{}) Load from config file\n{}) Back", config, config + 1);
+                
+                let choice = read_valid_stdin("Your choice: ", maplidator_int_index_limit(config + 1));
+                if choice == config {
+                    let filename = read_valid_stdin("Config file: ", maplidator_identity_trim);
+                    let loaded = File::open(&filename)
+                        .map_err(|e| e.to_string())
+                        .and_then(|file| AnyAllocator::load_config(&mut BufReader::new(file)).map_err(|e| e.to_string()));
+                    match loaded {
+                        Ok(allocator) => warehouse_allocator = allocator,
+                        Err(e) => {
+                            println!("Failed to load config: {}", e);
+                            continue
+                        }
+                    }
+                } else if choice < config {
+                    warehouse_allocator = allocators::REGISTRY[choice - 1].build();
+                } else {
+                    continue
+                }
+                println!("Now using {}", warehouse_allocator);
             }
             _ => { unreachable!() }
         }
     }
     
     println!("The warehouse is closed. Bye!");
+    ExitCode::SUCCESS
+}
+
+fn read_product_stdin(dimensions: WarehouseDimensions) -> AnyOldProduct {
+    let identifier = read_valid_stdin("Product identifier: ", |input| {
+        let input = input.trim();
+        input.parse().map_err(|_| "Failed to parse into number")
+    });
+    let name = read_valid_stdin("Product name: ", maplidator_identity_trim);
+    let amount: u64 = read_valid_stdin("Amount in stack: ", |input| {
+        let input = input.trim();
+        input.parse().map_err(|_| "Failed to parse into number")
+    });
+
+    let quality = read_valid_stdin(
+        "Select a category:\n1) Fragile\n2) Oversized\n3)Normal\nYour choice: ",
+    |input| {
+            let input = maplidator_int_index_limit(3)(input)?;
+            match input {
+                1 => {
+                    let expiry_date = read_valid_stdin("Expiry date (YYYY-MM-DD): ", maplidator_date);
+                    let max_row = read_valid_stdin("Max row: ", maplidator_int_index_limit(dimensions.rows));
+                    
+                    Ok(ProductCategory::Fragile { expiry_date, max_row })
+                }
+                2 => {
+                    let zone_count = read_valid_stdin("Zones occupied: ", maplidator_int_index_limit(dimensions.zones));
+                    
+                    Ok(ProductCategory::Oversized { zone_count })
+                }
+                3 => {
+                    Ok(ProductCategory::Normal)
+                }
+                _ => unreachable!()
+            }
+        }
+    );
+    
+    AnyOldProduct::new(identifier, name, amount, quality)
+}
+
+fn is_csv(filename: &str) -> bool {
+    filename.to_lowercase().ends_with(".csv")
+}
+
+fn is_binary(filename: &str) -> bool {
+    filename.to_lowercase().ends_with(".bin")
 }
 
 fn print_command_list() {
@@ -471,10 +770,22 @@ fn print_command_list() {
     println!("5) Search by name");
     println!("6) Search all locations for item");
     println!("7) Browse store");
-    println!("8) Search for expiry dates");
+    println!("8) Expiry report");
     println!("9) Quit");
-    println!("10) Import from JSON (Testing)");
-    println!("11) Export to JSON (Testing)");
+    println!("10) Import from JSON, CSV or binary");
+    println!("11) Export to JSON, CSV or binary");
+    println!("12) Manage admission filters");
+    println!("13) Move product");
+    println!("14) Pick, restock or split a stack");
+    println!("15) Receive a batch of products (all or nothing)");
+    println!("16) Audit journal");
+    println!("17) Undo");
+    println!("18) Redo");
+    println!("19) Sweep expired products");
+    println!("20) Pick by product, earliest expiry or oldest first");
+    println!("21) Oldest stock report");
+    println!("22) Re-slotting plan by pick velocity");
+    println!("23) Choose allocator");
 }
 
 /*
@@ -521,6 +832,20 @@ fn maplidator_yes_or_no(input: String) -> Result<bool, &'static str> {
     }
 }
 
+fn maplidator_date(input: String) -> Result<time::Date, &'static str> {
+    let mut input = input.trim().split('-');
+    let year= get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid year")?;
+    let month: u8 = get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid month")?;
+    let month = month.try_into().map_err(|_| "Invalid month")?;
+    let day = get_from_iterator_and_parse(&mut input).map_err(|_| "Could not find a valid day")?;
+
+    if input.next().is_some() {
+        return Err("Extra data found during parsing");
+    }
+
+    time::Date::from_calendar_date(year, month, day).map_err(|_| "Invalid date")
+}
+
 fn maplidator_identity_trim(input: String) -> Result<String, &'static str> {
     Ok(input.trim().to_string())
 }
@@ -555,7 +880,11 @@ where
         let mut input = String::new();
         print!("{}", prompt);
         std::io::stdout().flush().unwrap();
-        std::io::stdin().read_line(&mut input).unwrap();
+        if std::io::stdin().read_line(&mut input).unwrap() == 0 {
+            // Nothing more will ever be typed, so the prompt cannot be answered
+            println!();
+            std::process::exit(0);
+        }
         match maplidator(input) {
             Ok(value) => break value,
             Err(msg) => println!("{}", msg),
@@ -577,4 +906,4 @@ fn get_from_iterator_and_parse<'a, T: FromStr>(it: &mut impl Iterator<Item = &'a
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,309 @@
+use std::io::{BufRead, Write};
+use thiserror::Error;
+use crate::coords::StoreCoords;
+use crate::history::{History, HistoryError};
+use crate::warehouse::{ModificationError, Product, ProductCategory, Warehouse, WarehouseAllocator, WarehouseEntry};
+use crate::AnyOldProduct;
+
+/// Commands understood in a script, one per line
+/// Blank lines and lines starting with # are skipped, names with spaces go between double quotes
+pub const SCRIPT_HELP: &str = "\
+  add <id> \"<name>\" <amount> normal
+  add <id> \"<name>\" <amount> fragile <YYYY-MM-DD> <max row>
+  add <id> \"<name>\" <amount> oversized <extra zones>
+  remove <row> <shelf> <zone>
+  move <row> <shelf> <zone> <row> <shelf> <zone>
+  pick|restock <row> <shelf> <zone> <quantity>
+  split <row> <shelf> <zone> <quantity>
+  expect <row> <shelf> <zone> <id>|empty|placeholder
+  undo | redo";
+
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    #[error("{0}")]
+    Syntax(String),
+    #[error(transparent)]
+    Modification(#[from] ModificationError),
+    #[error(transparent)]
+    History(#[from] HistoryError),
+    #[error("Expected {expected} at {coords:?}, found {found}")]
+    Unexpected {
+        coords: StoreCoords,
+        expected: String,
+        found: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct ScriptFailure {
+    pub line: usize,
+    pub error: ScriptError,
+}
+
+#[derive(Debug, Default)]
+pub struct ScriptReport {
+    /// Commands that ran successfully
+    pub executed: usize,
+    pub failures: Vec<ScriptFailure>,
+    /// Whether the run ended early because of --stop-on-error, or the script could not be read
+    pub stopped: bool,
+}
+
+impl ScriptReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty() && !self.stopped
+    }
+}
+
+/// Runs every command in order, each one undoable on its own through the history
+/// Failures are written to the output with their line number as they happen
+pub fn run_script(
+    warehouse: &mut Warehouse<AnyOldProduct>,
+    history: &mut History<AnyOldProduct>,
+    allocator: &mut impl WarehouseAllocator<AnyOldProduct>,
+    script: impl BufRead,
+    stop_on_error: bool,
+    out: &mut impl Write,
+) -> ScriptReport {
+    let mut report = ScriptReport::default();
+
+    for (index, line) in script.lines().enumerate() {
+        let line_number = index + 1;
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                let _ = writeln!(out, "line {}: could not read script: {}", line_number, e);
+                report.stopped = true;
+                break;
+            }
+        };
+
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                report.failures.push(ScriptFailure { line: line_number, error: e });
+                Vec::new()
+            }
+        };
+        if !words.is_empty() {
+            match run_command(warehouse, history, allocator, &words) {
+                Ok(()) => report.executed += 1,
+                Err(error) => report.failures.push(ScriptFailure { line: line_number, error }),
+            }
+        }
+
+        if let Some(failure) = report.failures.last()
+            && failure.line == line_number {
+            let _ = writeln!(out, "line {}: {}", line_number, failure.error);
+            if stop_on_error {
+                report.stopped = true;
+                break;
+            }
+        }
+    }
+
+    report
+}
+
+fn run_command(
+    warehouse: &mut Warehouse<AnyOldProduct>,
+    history: &mut History<AnyOldProduct>,
+    allocator: &mut impl WarehouseAllocator<AnyOldProduct>,
+    words: &[String],
+) -> Result<(), ScriptError> {
+    let (command, args) = words.split_first().expect("Blank lines are skipped");
+
+    match command.as_str() {
+        "add" => {
+            let product = parse_product(args)?;
+            history.run(warehouse, |w| w.add_product(product, allocator))?;
+        }
+        "remove" => {
+            let [coords] = parse_coords::<1>(args, 0)?;
+            history.run(warehouse, |w| w.remove_product(coords))?;
+        }
+        "move" => {
+            let [from, to] = parse_coords::<2>(args, 0)?;
+            history.run(warehouse, |w| w.move_product(from, to))?;
+        }
+        "pick" | "restock" | "split" => {
+            let [coords] = parse_coords::<1>(args, 1)?;
+            let quantity = parse_number(&args[3], "quantity")?;
+            match command.as_str() {
+                "pick" => history.run(warehouse, |w| w.pick(&coords, quantity).map(|_| ()))?,
+                "restock" => history.run(warehouse, |w| w.restock(&coords, quantity).map(|_| ()))?,
+                _ => history.run(warehouse, |w| w.split_stack(&coords, quantity, allocator).map(|_| ()))?,
+            }
+        }
+        "expect" => {
+            let [coords] = parse_coords::<1>(args, 1)?;
+            let expected = args[3].as_str();
+            let found = match warehouse.get_product_ref(&coords)? {
+                WarehouseEntry::Some(product) => product.identifier().to_string(),
+                WarehouseEntry::None => "empty".to_string(),
+                WarehouseEntry::OversizedPlaceholder => "placeholder".to_string(),
+            };
+            if found != expected {
+                return Err(ScriptError::Unexpected { coords, expected: expected.to_string(), found });
+            }
+        }
+        "undo" | "redo" => {
+            expect_arguments(args, 0)?;
+            if command == "undo" {
+                history.undo(warehouse)?;
+            } else {
+                history.redo(warehouse)?;
+            }
+        }
+        other => return Err(ScriptError::Syntax(format!("Unknown command {}", other))),
+    }
+    Ok(())
+}
+
+fn parse_product(args: &[String]) -> Result<AnyOldProduct, ScriptError> {
+    if args.len() < 4 {
+        return Err(ScriptError::Syntax("add needs an identifier, name, amount and category".to_string()));
+    }
+    let identifier = parse_number(&args[0], "identifier")?;
+    let name = args[1].clone();
+    if name.is_empty() {
+        return Err(ScriptError::Syntax("Product name cannot be empty".to_string()));
+    }
+    let amount = parse_number(&args[2], "amount")?;
+
+    let category = &args[3..];
+    let quality = match category[0].as_str() {
+        "normal" => {
+            expect_arguments(category, 1)?;
+            ProductCategory::Normal
+        }
+        "fragile" => {
+            expect_arguments(category, 3)?;
+            let expiry_date = crate::maplidator_date(category[1].clone()).map_err(|e| ScriptError::Syntax(e.to_string()))?;
+            ProductCategory::Fragile { expiry_date, max_row: parse_number(&category[2], "max row")? }
+        }
+        "oversized" => {
+            expect_arguments(category, 2)?;
+            ProductCategory::Oversized { zone_count: parse_number(&category[1], "zone count")? }
+        }
+        other => return Err(ScriptError::Syntax(format!("Unknown category {}", other))),
+    };
+
+    Ok(AnyOldProduct::new(identifier, name, amount, quality))
+}
+
+// Reads N coordinates from the start of the arguments, which must be followed by exactly `extra` more
+fn parse_coords<const N: usize>(args: &[String], extra: usize) -> Result<[StoreCoords; N], ScriptError> {
+    expect_arguments(args, N * 3 + extra)?;
+    let mut coords = std::array::from_fn(|_| StoreCoords(0, 0, 0));
+    for (i, c) in coords.iter_mut().enumerate() {
+        let part = &args[i * 3..i * 3 + 3];
+        *c = StoreCoords(parse_number(&part[0], "row")?, parse_number(&part[1], "shelf")?, parse_number(&part[2], "zone")?);
+    }
+    Ok(coords)
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str, what: &str) -> Result<T, ScriptError> {
+    word.parse().map_err(|_| ScriptError::Syntax(format!("Invalid {}: {}", what, word)))
+}
+
+fn expect_arguments(args: &[String], count: usize) -> Result<(), ScriptError> {
+    if args.len() != count {
+        return Err(ScriptError::Syntax(format!("Expected {} arguments, found {}", count, args.len())));
+    }
+    Ok(())
+}
+
+// Splits on whitespace, keeping double quoted text together
+fn split_words(line: &str) -> Result<Vec<String>, ScriptError> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut word = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => word.extend(chars.next()),
+                    Some(c) => word.push(c),
+                    None => return Err(ScriptError::Syntax("Unterminated quote".to_string())),
+                }
+            }
+            words.push(word);
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            words.push(word);
+        }
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    fn run(script: &str, stop_on_error: bool) -> (Warehouse<AnyOldProduct>, ScriptReport, String) {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut history = History::new(10);
+        let mut out = Vec::new();
+        let report = run_script(&mut warehouse, &mut history, &mut WarehouseAllocatorClosestFirstEfficient, script.as_bytes(), stop_on_error, &mut out);
+        (warehouse, report, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_script_runs_in_order() {
+        let script = r#"
+            # Nightly receiving
+            add 9 Beam 1 oversized 2
+            add 42 "Hex Bolts" 100 normal
+            add 7 "Milk" 12 fragile 2030-01-01 1
+            expect 0 0 3 42
+            expect 0 0 2 placeholder
+            move 0 0 3 1 1 3
+            pick 1 1 3 40
+            split 1 1 3 10
+            remove 0 1 0
+            undo
+            expect 0 1 0 7
+        "#;
+        let (warehouse, report, out) = run(script, false);
+        assert_eq!(out, "");
+        assert!(report.is_ok());
+        assert_eq!(report.executed, 11);
+        assert_eq!(warehouse.quantity_by_id(&42), Some(60));
+        assert_eq!(warehouse.search_by_name("Hex Bolts").map(Vec::len), Some(2));
+        assert_eq!(warehouse.search_by_id(&7), Some(&vec![StoreCoords(0, 1, 0)]));
+        assert_eq!(warehouse.search_by_id(&42), Some(&vec![StoreCoords(1, 1, 3), StoreCoords(0, 0, 3)]));
+    }
+
+    #[test]
+    fn test_failures_report_line_numbers() {
+        let script = "add 1 Bolts 5 normal\nremove 1 1 1\nadd 2 \"Nuts 5 normal\nfly\nremove 0 0 9\nadd 3 Washers 5 normal\n";
+        let (warehouse, report, out) = run(script, false);
+        let lines: Vec<usize> = report.failures.iter().map(|f| f.line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5]);
+        assert_eq!(out.lines().count(), 4);
+        assert!(out.starts_with("line 2: No product in location"));
+        assert_eq!(report.executed, 2);
+        assert!(!report.stopped);
+        assert!(warehouse.search_by_id(&3).is_some());
+
+        let (warehouse, report, _) = run(script, true);
+        assert!(report.stopped);
+        assert_eq!(report.failures.len(), 1);
+        assert!(warehouse.search_by_id(&3).is_none());
+    }
+}
@@ -3,13 +3,30 @@ use std::collections::BTreeMap;
 use std::mem;
 use std::ops::RangeBounds;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
-use crate::coords::StoreCoords;
-use crate::free_map::FreeMap;
+use crate::allocators::AnyAllocator;
+use crate::coords::{StoreCoords, WarehouseDimensions};
+use crate::filters::FilterRule;
+use crate::free_map::{FreeMap, RunConstraints};
+use crate::journal::JournalSink;
 
-#[derive(Hash, Serialize, Deserialize)]
+mod transaction;
+mod integrity;
+mod snapshot;
+mod binary;
+mod expiry;
+mod picking;
+mod velocity;
+
+pub use transaction::WarehouseChange;
+pub use integrity::IntegrityReport;
+pub use expiry::{ExpiryHorizons, ExpiryReport, SweepAction};
+pub use picking::{PickMode, PickTarget};
+pub use velocity::{AbcThresholds, VelocityClass};
+
+#[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum ProductCategory {
     Normal,
     Fragile {
@@ -17,7 +34,7 @@ pub enum ProductCategory {
         max_row: usize
     },
     Oversized {
-        // num of extra zones, cannot be higher than warehouse's max_idx
+        // num of extra zones, must be lower than the warehouse's zones per shelf
         zone_count: usize,
     }
 }
@@ -38,7 +55,7 @@ impl Display for ProductCategory {
     }
 }
 
-pub trait Product: Ord + Display + Serialize + DeserializeOwned + Default {
+pub trait Product: Ord + Display + Serialize + DeserializeOwned + Default + Clone {
     fn identifier(&self) -> &i64;
     fn name(&self) -> &String;
     fn amount(&self) -> u64;
@@ -46,6 +63,17 @@ pub trait Product: Ord + Display + Serialize + DeserializeOwned + Default {
     fn timestamp(&self) -> time::UtcDateTime;
 
     fn set_timestamp(&mut self, timestamp: time::UtcDateTime);
+    
+    fn set_amount(&mut self, amount: u64);
+}
+
+/// Zones taken by the product, and where they may go
+pub fn product_run<I: Product>(product: &I) -> (usize, RunConstraints) {
+    match product.quality() {
+        ProductCategory::Normal => (1, RunConstraints::default()),
+        ProductCategory::Fragile { max_row, .. } => (1, RunConstraints::rows(0..=*max_row)),
+        ProductCategory::Oversized { zone_count } => (zone_count + 1, RunConstraints::default()),
+    }
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -61,17 +89,6 @@ impl<I> WarehouseEntry<I> {
         matches!(*self, WarehouseEntry::Some(_))
     }
 
-    fn is_none(&self) -> bool {
-        matches!(self, WarehouseEntry::None | WarehouseEntry::OversizedPlaceholder)
-    }
-
-    fn expect(self, msg: &str) -> I {
-        match self {
-            WarehouseEntry::Some(val) => {val}
-            _ => panic!("{}", msg)
-        }
-    }
-    
     pub fn expect_ref(&self, msg: &str) -> &I {
         match self {
             WarehouseEntry::Some(val) => val,
@@ -81,28 +98,51 @@ impl<I> WarehouseEntry<I> {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "I: Product", deserialize = "I: Product"))]
 pub struct Warehouse<I> {
-    store_max_idx: usize,
+    dimensions: WarehouseDimensions,
     store: Vec<Vec<Vec<WarehouseEntry<I>>>>,
     // Não é preciso nenhuma trait aqui, mas a especificação diz trait
-    #[serde(skip)]
+    // Only filters backed by a FilterRule are persisted, custom ones are dropped
+    #[serde(with = "crate::filters::persisted")]
     filters: Vec<Box<dyn WarehouseAdmissionFilter<I>>>,
     store_index_by_name: BTreeMap<String, Vec<StoreCoords>>,
     store_index_by_id: BTreeMap<i64, Vec<StoreCoords>>,
+    // JSON object keys must be strings, so this one is stored as a list of pairs
+    #[serde(with = "expiry_index")]
     store_index_expiry_dates: BTreeMap<time::Date, Vec<i64>>,
+    // Not persisted, it is rebuilt from the products when a snapshot is loaded
+    #[serde(skip)]
+    store_index_timestamps: BTreeMap<time::UtcDateTime, Vec<StoreCoords>>,
     free_map: crate::free_map::FreeMap,
+    // Picks and removals of each identifier, saved beside the warehouse in snapshots
+    #[serde(skip)]
+    pick_counts: BTreeMap<i64, u64>,
+    // Allocator chosen for this warehouse with its state, also saved beside it in snapshots
+    #[serde(skip)]
+    allocator: Option<AnyAllocator>,
+    // Changes made inside the current transaction, if any
+    #[serde(skip)]
+    pending_changes: Option<Vec<WarehouseChange<I>>>,
+    // Identifiers picked inside the current transaction, only counted once it commits
+    #[serde(skip)]
+    pending_picks: Option<Vec<i64>>,
+    #[serde(skip)]
+    journal: Option<Box<dyn JournalSink<I>>>,
+    #[serde(skip)]
+    operator: Option<String>,
 }
 
 impl<I: Product> Warehouse<I> {
-    pub fn new(store_max_idx: usize) -> Warehouse<I> {
+    pub fn new(dimensions: WarehouseDimensions) -> Warehouse<I> {
         let store = Vec::from_iter(
-            (0..store_max_idx)
+            (0..dimensions.rows)
                 .map(|_| {
                     Vec::from_iter(
-                        (0..store_max_idx)
+                        (0..dimensions.shelves)
                             .map(|_| {
                                 Vec::from_iter(
-                                    (0..store_max_idx)
+                                    (0..dimensions.zones)
                                         .map(|_| {
                                             WarehouseEntry::<I>::None
                                         })
@@ -112,168 +152,377 @@ impl<I: Product> Warehouse<I> {
                 }));
         
         Warehouse {
-            store_max_idx,
+            dimensions,
             store,
             filters: Vec::new(),
             store_index_by_name: BTreeMap::new(),
             store_index_by_id: BTreeMap::new(),
             store_index_expiry_dates: BTreeMap::new(),
-            free_map: FreeMap::new(store_max_idx),
+            store_index_timestamps: BTreeMap::new(),
+            free_map: FreeMap::new(dimensions),
+            pick_counts: BTreeMap::new(),
+            allocator: None,
+            pending_changes: None,
+            pending_picks: None,
+            journal: None,
+            operator: None,
         }
     }
 
-    fn verify_product_filters(&mut self, product: &I) -> bool {
+    fn verify_product_filters(&mut self, product: &I) -> Result<(), ModificationError> {
         let mut filters = mem::take(&mut self.filters);
-        let result = filters.iter_mut().all(|filter| filter.check(self, product));
+        let result = filters.iter_mut().try_for_each(|filter| {
+            filter.check(self, product).map_err(|reason| ModificationError::NotAllowed {
+                filter: filter.name().to_string(),
+                reason,
+            })
+        });
         self.filters = filters;
         result
     }
     
+    pub fn add_filter(&mut self, filter: Box<dyn WarehouseAdmissionFilter<I>>) -> Result<(), FilterError> {
+        if self.filters.iter().any(|f| f.name() == filter.name()) {
+            return Err(FilterError::DuplicateName(filter.name().to_string()));
+        }
+        
+        self.filters.push(filter);
+        Ok(())
+    }
+    
+    pub fn add_rule(&mut self, rule: FilterRule) -> Result<(), FilterError> {
+        self.add_filter(rule.instantiate()?)
+    }
+    
+    pub fn remove_filter(&mut self, name: &str) -> Option<Box<dyn WarehouseAdmissionFilter<I>>> {
+        let position = self.filters.iter().position(|f| f.name() == name)?;
+        Some(self.filters.remove(position))
+    }
+    
+    pub fn list_filters(&self) -> impl ExactSizeIterator<Item = &dyn WarehouseAdmissionFilter<I>> {
+        self.filters.iter().map(|f| f.as_ref())
+    }
+    
+    /// Allocator saved with the warehouse, None until one is chosen
+    pub fn allocator(&self) -> Option<&AnyAllocator> {
+        self.allocator.as_ref()
+    }
+    
+    // Callers keep their own copy while working, and hand it back before saving so its state is kept
+    pub fn set_allocator(&mut self, allocator: Option<AnyAllocator>) {
+        self.allocator = allocator;
+    }
+    
     pub fn add_product(&mut self, product: I, allocator: &mut impl WarehouseAllocator<I>) -> Result<(), ModificationError> {
-        if !self.verify_product_filters(&product) {
-            return Err(ModificationError::NotAllowed);
+        self.verify_product_filters(&product)?;
+        // No allocator can place a product longer than a shelf
+        if Self::extra_zones(&product) >= self.dimensions.zones {
+            return Err(ModificationError::TooBig);
         }
 
         let store_coords = match allocator.next(self, &product) {
             Some(store_coords) => store_coords,
             None => return Err(ModificationError::Full)
         };
-        if let ProductCategory::Fragile { max_row, .. } = product.quality() {
-            if store_coords.0 > *max_row {
-                return Err(ModificationError::Fragile);
-            }
+        
+        self.check_placement(&product, &store_coords)?;
+        let recorded = self.is_recording().then(|| product.clone());
+        self.place_product(product, store_coords.clone(), None);
+        if let Some(product) = recorded {
+            self.record(WarehouseChange::Added { coords: store_coords, product, positions: None });
         }
-        let row = self.store.get_mut(store_coords.0).expect("Allocator output invalid");
-        let shelf = row.get_mut(store_coords.1).expect("Allocator output invalid");
+        Ok(())
+    }
+    
+    pub fn remove_product(&mut self, store_coords: StoreCoords) -> Result<(), ModificationError> {
+        let (product, positions) = self.take_product(&store_coords)?;
+        self.count_pick(*product.identifier());
+        self.record(WarehouseChange::Removed { coords: store_coords, product, positions });
+        Ok(())
+    }
+    
+    // Relocates a product without going through the filters, keeping its timestamp
+    // On failure the product is left where it was
+    pub fn move_product(&mut self, from: StoreCoords, to: StoreCoords) -> Result<(), ModificationError> {
+        self.relocate(from, to, None)
+    }
+
+    // Same as move_product, putting the product back at the given places in the indices if any
+    fn relocate(&mut self, from: StoreCoords, to: StoreCoords, to_positions: Option<&IndexPositions>) -> Result<(), ModificationError> {
+        let (product, positions) = self.take_product(&from)?;
         
-        match product.quality() {
-            ProductCategory::Oversized { zone_count } => {
-                if zone_count >= &self.store_max_idx {
-                    return Err(ModificationError::TooBig)
-                }
-                
-                let zone = shelf.get_mut(store_coords.2..=store_coords.2+zone_count).expect("Allocator output invalid");
-                
-                // do all checks first
-                for z in &*zone {
-                    match z {
-                        WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {
-                            return Err(ModificationError::Occupied);
-                        }
-                        WarehouseEntry::None => {}
-                    }
-                }
-                
-                let (zone, placeholders) = zone.split_first_mut().expect("Allocator output invalid");
-                
-                self.store_index_by_name.entry(product.name().clone()).or_default().push(store_coords.clone());
-                self.store_index_by_id.entry(*product.identifier()).or_default().push(store_coords.clone());
-                if let ProductCategory::Fragile { expiry_date, .. } = product.quality() {
-                    self.store_index_expiry_dates.entry(*expiry_date).or_default().push(*product.identifier());
-                }
-                self.free_map.occupy_range(store_coords.clone()..=(store_coords.0,store_coords.1,store_coords.2+zone_count).into());
-                *zone = WarehouseEntry::Some(product);
-                
-                for place in placeholders {
-                    *place = WarehouseEntry::OversizedPlaceholder
-                }
-                
-                Ok(())
-            }
-            _ => {
-                let zone = shelf.get_mut(store_coords.2).expect("Allocator output invalid");
-
-                match zone {
-                    WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {
-                        Err(ModificationError::Occupied)
-                    }
-                    WarehouseEntry::None => {
-                        self.store_index_by_name.entry(product.name().clone()).or_default().push(store_coords.clone());
-                        self.store_index_by_id.entry(*product.identifier()).or_default().push(store_coords.clone());
-                        if let ProductCategory::Fragile { expiry_date, .. } = product.quality() {
-                            self.store_index_expiry_dates.entry(*expiry_date).or_default().push(*product.identifier());
-                        }
-                        self.free_map.occupy_single(store_coords);
-                        *zone = WarehouseEntry::Some(product);
-                        Ok(())
-                    }
+        // The product's own zones are free at this point, so it may shift inside its span
+        if let Err(e) = self.check_placement(&product, &to) {
+            self.place_product(product, from, Some(&positions));
+            return Err(e);
+        }
+        
+        self.place_product(product, to.clone(), to_positions);
+        self.record(WarehouseChange::Moved { from, to, positions, to_positions: None });
+        Ok(())
+    }
+    
+    // Takes units out of a stack, the stack is removed once empty
+    // Returns the units left in the stack
+    pub fn pick(&mut self, store_coords: &StoreCoords, quantity: u64) -> Result<u64, ModificationError> {
+        let product = self.product_mut(store_coords)?;
+        if quantity == 0 {
+            return Err(ModificationError::InvalidAmount);
+        }
+        
+        let available = product.amount();
+        let remaining = available.checked_sub(quantity).ok_or(ModificationError::InsufficientAmount { available })?;
+        let identifier = *product.identifier();
+        if remaining == 0 {
+            let (product, positions) = self.take_product(store_coords)?;
+            self.record(WarehouseChange::Removed { coords: store_coords.clone(), product, positions });
+        } else {
+            product.set_amount(remaining);
+            self.record(WarehouseChange::AmountChanged { coords: store_coords.clone(), before: available, after: remaining });
+        }
+        self.count_pick(identifier);
+        
+        Ok(remaining)
+    }
+    
+    // Returns the new amount in the stack
+    pub fn restock(&mut self, store_coords: &StoreCoords, quantity: u64) -> Result<u64, ModificationError> {
+        let product = self.product_mut(store_coords)?;
+        if quantity == 0 {
+            return Err(ModificationError::InvalidAmount);
+        }
+        
+        let before = product.amount();
+        let amount = before.checked_add(quantity).ok_or(ModificationError::InvalidAmount)?;
+        product.set_amount(amount);
+        self.record(WarehouseChange::AmountChanged { coords: store_coords.clone(), before, after: amount });
+        Ok(amount)
+    }
+    
+    // Moves part of a stack into a new stack placed by the allocator
+    // The new stack goes through the filters like any other product, and keeps the original timestamp
+    pub fn split_stack(&mut self, store_coords: &StoreCoords, quantity: u64, allocator: &mut impl WarehouseAllocator<I>) -> Result<StoreCoords, ModificationError> {
+        let product = self.product_ref(store_coords)?;
+        let available = product.amount();
+        if quantity == 0 {
+            return Err(ModificationError::InvalidAmount);
+        }
+        if quantity >= available {
+            return Err(ModificationError::InsufficientAmount { available });
+        }
+        
+        let mut new_stack = product.clone();
+        new_stack.set_amount(quantity);
+        self.verify_product_filters(&new_stack)?;
+        
+        let new_coords = allocator.next(self, &new_stack).ok_or(ModificationError::Full)?;
+        self.check_placement(&new_stack, &new_coords)?;
+        
+        self.product_mut(store_coords)?.set_amount(available - quantity);
+        self.record(WarehouseChange::AmountChanged { coords: store_coords.clone(), before: available, after: available - quantity });
+        let recorded = self.is_recording().then(|| new_stack.clone());
+        self.place_product(new_stack, new_coords.clone(), None);
+        if let Some(product) = recorded {
+            self.record(WarehouseChange::Added { coords: new_coords.clone(), product, positions: None });
+        }
+        Ok(new_coords)
+    }
+    
+    fn product_ref(&self, store_coords: &StoreCoords) -> Result<&I, ModificationError> {
+        if !self.validate_coords(store_coords) {
+            return Err(ModificationError::InvalidCoords);
+        }
+        
+        match self.entry(store_coords) {
+            WarehouseEntry::Some(p) => Ok(p),
+            WarehouseEntry::None => Err(ModificationError::NotFound),
+            WarehouseEntry::OversizedPlaceholder => Err(ModificationError::Placeholder),
+        }
+    }
+    
+    fn product_mut(&mut self, store_coords: &StoreCoords) -> Result<&mut I, ModificationError> {
+        if !self.validate_coords(store_coords) {
+            return Err(ModificationError::InvalidCoords);
+        }
+        
+        match &mut self.store[store_coords.0][store_coords.1][store_coords.2] {
+            WarehouseEntry::Some(p) => Ok(p),
+            WarehouseEntry::None => Err(ModificationError::NotFound),
+            WarehouseEntry::OversizedPlaceholder => Err(ModificationError::Placeholder),
+        }
+    }
+    
+    // Number of extra zones used by the product, after its own
+    fn extra_zones(product: &I) -> usize {
+        if let ProductCategory::Oversized { zone_count } = product.quality() {
+            *zone_count
+        } else { 0 }
+    }
+    
+    fn check_placement(&self, product: &I, store_coords: &StoreCoords) -> Result<(), ModificationError> {
+        if !self.validate_coords(store_coords) {
+            return Err(ModificationError::InvalidCoords);
+        }
+        if let ProductCategory::Fragile { max_row, .. } = product.quality()
+            && store_coords.0 > *max_row {
+            return Err(ModificationError::Fragile);
+        }
+        
+        let zone_count = Self::extra_zones(product);
+        if zone_count >= self.dimensions.zones {
+            return Err(ModificationError::TooBig)
+        }
+        
+        let shelf = &self.store[store_coords.0][store_coords.1];
+        let zones = shelf.get(store_coords.2..=store_coords.2+zone_count).ok_or(ModificationError::InvalidCoords)?;
+        
+        for z in zones {
+            match z {
+                WarehouseEntry::Some(_) | WarehouseEntry::OversizedPlaceholder => {
+                    return Err(ModificationError::Occupied);
                 }
+                WarehouseEntry::None => {}
             }
         }
+        
+        Ok(())
     }
     
-    pub fn remove_product(&mut self, store_coords: StoreCoords) -> Result<(), ModificationError> {
+    // Must only be called after check_placement succeeds
+    // Products are appended to the indices, unless their previous positions are given
+    fn place_product(&mut self, product: I, store_coords: StoreCoords, positions: Option<&IndexPositions>) {
+        let zone_count = Self::extra_zones(&product);
+        
+        index_insert(self.store_index_by_name.entry(product.name().clone()).or_default(), store_coords.clone(), positions.map(|p| p.name));
+        index_insert(self.store_index_by_id.entry(*product.identifier()).or_default(), store_coords.clone(), positions.map(|p| p.id));
+        if let ProductCategory::Fragile { expiry_date, .. } = product.quality() {
+            index_insert(self.store_index_expiry_dates.entry(*expiry_date).or_default(), *product.identifier(), positions.and_then(|p| p.expiry));
+        }
+        index_insert(self.store_index_timestamps.entry(product.timestamp()).or_default(), store_coords.clone(), positions.map(|p| p.timestamp));
+        
         let shelf = &mut self.store[store_coords.0][store_coords.1];
-        let place = &mut shelf[store_coords.2];
-        let mut oversized_count = 0_usize;
-        let result_1 = match place {
+        let (zone, placeholders) = shelf[store_coords.2..=store_coords.2+zone_count].split_first_mut()
+            .expect("Range is never empty");
+        *zone = WarehouseEntry::Some(product);
+        for place in placeholders {
+            *place = WarehouseEntry::OversizedPlaceholder
+        }
+        
+        if zone_count > 0 {
+            self.free_map.occupy_range(store_coords.clone()..=(store_coords.0,store_coords.1,store_coords.2+zone_count).into());
+        } else {
+            self.free_map.occupy_single(store_coords);
+        }
+    }
+    
+    // Removes the product from the store and all indices, handing it back
+    fn take_product(&mut self, store_coords: &StoreCoords) -> Result<(I, IndexPositions), ModificationError> {
+        if !self.validate_coords(store_coords) {
+            return Err(ModificationError::InvalidCoords);
+        }
+        
+        let shelf = &mut self.store[store_coords.0][store_coords.1];
+        let product = match mem::take(&mut shelf[store_coords.2]) {
+            WarehouseEntry::Some(p) => p,
             WarehouseEntry::None => {
-                Err(ModificationError::NotFound)
-            }
-            WarehouseEntry::Some(p) => {
-                let map_entry = self.store_index_by_name.get_mut(p.name())
-                    .expect("Existing product should be indexed in map");
-                map_entry.remove(map_entry.iter().position(|x| {
-                    *x == store_coords
-                }).expect("Existing product should be indexed in map"));
-                if map_entry.is_empty() {
-                    self.store_index_by_name.remove(p.name());
-                }
-                let map_entry = self.store_index_by_id.get_mut(p.identifier())
-                    .expect("Existing product should be indexed in map");
-                map_entry.remove(map_entry.iter().position(|x| {
-                    *x == store_coords
-                }).expect("Existing product should be indexed in map"));
-                if map_entry.is_empty() {
-                    self.store_index_by_id.remove(p.identifier());
-                }
-                
-                if let ProductCategory::Fragile { expiry_date, .. } = p.quality() {
-                    let map_entry = self.store_index_expiry_dates.get_mut(expiry_date)
-                        .expect("Existing product should be indexed in map");
-                    map_entry.remove(map_entry.iter().position(|x| {
-                        *x == *p.identifier()
-                    }).expect("Existing product should be indexed in map"));
-                    if map_entry.is_empty() {
-                        self.store_index_expiry_dates.remove(expiry_date);
-                    }
-                }
-                
-                if let ProductCategory::Oversized { zone_count } = p.quality() {
-                    oversized_count = *zone_count;
-                    self.free_map.free_range(store_coords.clone()..=(store_coords.0,store_coords.1, store_coords.2+zone_count).into());
-                } else {
-                    self.free_map.free_single(store_coords.clone());
-                }
-                
-                *place = WarehouseEntry::None;
-                Ok(())
+                return Err(ModificationError::NotFound)
             }
             WarehouseEntry::OversizedPlaceholder => {
-                Err(ModificationError::Placeholder)
+                shelf[store_coords.2] = WarehouseEntry::OversizedPlaceholder;
+                return Err(ModificationError::Placeholder)
             }
         };
         
-        if oversized_count > 0 {
-            let places = shelf.get_mut(store_coords.2+1..=store_coords.2+oversized_count)
+        let zone_count = Self::extra_zones(&product);
+        if zone_count > 0 {
+            let places = shelf.get_mut(store_coords.2+1..=store_coords.2+zone_count)
                 .expect("Store in invalid state. <AfterAdd,CaughtOnRemove>");
             
             for place in places {
                 *place = WarehouseEntry::None;
             }
+            self.free_map.free_range(store_coords.clone()..=(store_coords.0,store_coords.1, store_coords.2+zone_count).into());
+        } else {
+            self.free_map.free_single(store_coords.clone());
         }
         
-        result_1
+        let mut positions = IndexPositions::default();
+        
+        let map_entry = self.store_index_by_name.get_mut(product.name())
+            .expect("Existing product should be indexed in map");
+        positions.name = map_entry.iter().position(|x| {
+            x == store_coords
+        }).expect("Existing product should be indexed in map");
+        map_entry.remove(positions.name);
+        if map_entry.is_empty() {
+            self.store_index_by_name.remove(product.name());
+        }
+        let map_entry = self.store_index_by_id.get_mut(product.identifier())
+            .expect("Existing product should be indexed in map");
+        positions.id = map_entry.iter().position(|x| {
+            x == store_coords
+        }).expect("Existing product should be indexed in map");
+        map_entry.remove(positions.id);
+        if map_entry.is_empty() {
+            self.store_index_by_id.remove(product.identifier());
+        }
+        
+        if let ProductCategory::Fragile { expiry_date, .. } = product.quality() {
+            let map_entry = self.store_index_expiry_dates.get_mut(expiry_date)
+                .expect("Existing product should be indexed in map");
+            let position = map_entry.iter().position(|x| {
+                *x == *product.identifier()
+            }).expect("Existing product should be indexed in map");
+            map_entry.remove(position);
+            positions.expiry = Some(position);
+            if map_entry.is_empty() {
+                self.store_index_expiry_dates.remove(expiry_date);
+            }
+        }
+        
+        let map_entry = self.store_index_timestamps.get_mut(&product.timestamp())
+            .expect("Existing product should be indexed in map");
+        positions.timestamp = map_entry.iter().position(|x| {
+            x == store_coords
+        }).expect("Existing product should be indexed in map");
+        map_entry.remove(positions.timestamp);
+        if map_entry.is_empty() {
+            self.store_index_timestamps.remove(&product.timestamp());
+        }
+        
+        Ok((product, positions))
+    }
+    
+    pub fn get_product_ref(&self, store_coords: &StoreCoords) -> Result<&WarehouseEntry<I>, ModificationError> {
+        if !self.validate_coords(store_coords) {
+            return Err(ModificationError::InvalidCoords);
+        }
+        Ok(self.entry(store_coords))
     }
     
-    pub fn get_product_ref(&self, store_coords: &StoreCoords) -> &WarehouseEntry<I> {
+    // Coordinates must have been validated already
+    fn entry(&self, store_coords: &StoreCoords) -> &WarehouseEntry<I> {
         &self.store[store_coords.0][store_coords.1][store_coords.2]
     }
     
+    // Coordinates of the product occupying a zone, following Oversized placeholders back to their product
+    pub fn find_owner(&self, store_coords: &StoreCoords) -> Option<StoreCoords> {
+        if !self.validate_coords(store_coords) {
+            return None;
+        }
+        
+        let shelf = &self.store[store_coords.0][store_coords.1];
+        shelf[..=store_coords.2].iter().enumerate().rev()
+            .find_map(|(zone, entry)| match entry {
+                WarehouseEntry::Some(_) => Some(Some((store_coords.0, store_coords.1, zone).into())),
+                WarehouseEntry::None => Some(None),
+                WarehouseEntry::OversizedPlaceholder => None,
+            })
+            .flatten()
+    }
+    
     fn validate_coords(&self, store_coords: &StoreCoords) -> bool {
-        self.store.get(store_coords.0)
-            .and_then(|row| row.get(store_coords.1))
-            .and_then(|shelf| shelf.get(store_coords.2)).is_some()
+        self.dimensions.contains(store_coords)
     }
     
     // find a product interactively, returns coordinates for the product
@@ -334,6 +583,14 @@ impl<I: Product> Warehouse<I> {
         self.store_index_by_id.get(id)
     }
     
+    // Total units stored across all stacks of an identifier
+    pub fn quantity_by_id(&self, id: &i64) -> Option<u64> {
+        let coords = self.store_index_by_id.get(id)?;
+        Some(coords.iter()
+            .map(|c| self.entry(c).expect_ref("Only Some values in map").amount())
+            .sum())
+    }
+    
     pub fn search_expiry_dates<R>(&self, range: R) -> impl Iterator<Item = (&time::Date, &Vec<i64>)>
     where
         R: RangeBounds<time::Date>
@@ -345,8 +602,8 @@ impl<I: Product> Warehouse<I> {
         &self.store
     }
     
-    pub fn store_max_idx(&self) -> usize {
-        self.store_max_idx
+    pub fn dimensions(&self) -> WarehouseDimensions {
+        self.dimensions
     }
     
     pub fn list_by_name(&self) -> impl ExactSizeIterator<Item = (&String,&Vec<StoreCoords>)> {
@@ -356,30 +613,58 @@ impl<I: Product> Warehouse<I> {
     pub fn free_map(&self) -> &FreeMap {
         &self.free_map
     }
-    
-    pub fn to_json(&self, writer: &mut impl std::io::Write) {
-        serde_json::to_writer_pretty(writer, self).unwrap()
+}
+
+mod expiry_index {
+    use std::collections::BTreeMap;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(index: &BTreeMap<time::Date, Vec<i64>>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(index.iter())
     }
-    
-    pub fn from_json(reader: &mut impl std::io::BufRead) -> Result<Self, ()> {
-        serde_json::from_reader(reader).map_err(|_| ())
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<time::Date, Vec<i64>>, D::Error> {
+        let pairs = Vec::<(time::Date, Vec<i64>)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
     }
 }
 
-pub trait WarehouseAllocator<I: Product> {
-    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords>;
+// Where a product sat inside each index, so it can be put back in the same order
+#[derive(Default, Clone, Debug)]
+pub struct IndexPositions {
+    name: usize,
+    id: usize,
+    expiry: Option<usize>,
+    timestamp: usize,
 }
 
-trait WarehouseAdmissionFilter<I: Product> {
-    /// Returns true if allowed, false if not
-    /// This method will not have access to the active filters in the warehouse (The list will always be empty)
-    fn check(&mut self, warehouse: &Warehouse<I>, product: &I) -> bool;
+fn index_insert<T>(list: &mut Vec<T>, value: T, position: Option<usize>) {
+    match position {
+        // Past the end only if the indices changed since the position was taken
+        Some(position) => list.insert(position.min(list.len()), value),
+        None => list.push(value),
+    }
+}
+
+pub trait WarehouseAllocator<I: Product> {
+    fn next(&mut self, warehouse: &Warehouse<I>, product: &I) -> Option<StoreCoords>;
 }
 
-impl<I: Product> WarehouseAdmissionFilter<I> for dyn FnMut(&Warehouse<I>, &I) -> bool {
-    fn check(&mut self, warehouse: &Warehouse<I>, product: &I) -> bool {
-        self(warehouse, product)
+pub trait WarehouseAdmissionFilter<I: Product> {
+    /// Unique name of the filter inside a warehouse
+    fn name(&self) -> &str;
+    
+    /// Short human readable explanation of the rule
+    fn description(&self) -> String;
+    
+    /// Declarative form of the filter, filters without one are not saved with the warehouse
+    fn rule(&self) -> Option<FilterRule> {
+        None
     }
+    
+    /// Returns Ok if allowed, or the reason for the rejection if not
+    /// This method will not have access to the active filters in the warehouse (The list will always be empty)
+    fn check(&mut self, warehouse: &Warehouse<I>, product: &I) -> Result<(), String>;
 }
 
 #[derive(Debug, Error)]
@@ -390,14 +675,216 @@ pub enum ModificationError {
     NotFound,
     #[error("Could not find a place for the product")]
     Full,
-    #[error("Product is not allowed in due to current filters")]
-    NotAllowed,
+    #[error("Product is not allowed in by filter {filter}: {reason}")]
+    NotAllowed {
+        filter: String,
+        reason: String,
+    },
     #[error("Placeholders cannot be manipulated directly, operate on the item instead")]
     Placeholder,
     #[error("Fragile item cannot be placed at location specified")]
     Fragile,
     #[error("Oversized item does not fit inside warehouse")]
-    TooBig
+    TooBig,
+    #[error("Location is outside the warehouse")]
+    InvalidCoords,
+    #[error("Not enough units in stack, only {available} available")]
+    InsufficientAmount {
+        available: u64,
+    },
+    #[error("Amount must be above zero and fit in a stack")]
+    InvalidAmount,
+}
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("A filter named {0} already exists")]
+    DuplicateName(String),
+    #[error("Invalid filter rule: {0}")]
+    InvalidRule(String),
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Could not access snapshot: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Corrupt snapshot at line {line}, column {column}: {message}")]
+    Corrupt {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("Warehouse could not be written as a snapshot: {0}")]
+    Unserializable(String),
+    #[error("Snapshot does not describe a valid warehouse: {0}")]
+    Invalid(String),
+    #[error("Snapshot has schema version {found}, only up to {supported} is supported")]
+    VersionMismatch {
+        found: u64,
+        supported: u64,
+    },
+    #[error("Snapshot is inconsistent and could not be repaired ({} problems, first: {})", .0.issues.len(), .0.issues.first().map(|i| i.to_string()).unwrap_or_default())]
+    Unrepairable(IntegrityReport),
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            serde_json::error::Category::Io => SnapshotError::Io(e.into()),
+            serde_json::error::Category::Syntax | serde_json::error::Category::Data | serde_json::error::Category::Eof if e.line() > 0 => {
+                SnapshotError::Corrupt { line: e.line(), column: e.column(), message: e.to_string() }
+            }
+            _ => SnapshotError::Unserializable(e.to_string()),
+        }
+    }
 }
 
-pub struct BrowserError;
\ No newline at end of file
+#[derive(Debug, Error)]
+#[error("Browse cancelled")]
+pub struct BrowserError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    #[test]
+    fn test_non_cubic_store() {
+        let warehouse = Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(4, 30, 12));
+        assert_eq!(warehouse.store().len(), 4);
+        assert!(warehouse.store().iter().all(|row| row.len() == 30));
+        assert!(warehouse.store().iter().flatten().all(|shelf| shelf.len() == 12));
+    }
+
+    #[test]
+    fn test_oversized_limited_by_zones() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 30, 12));
+        let product = AnyOldProduct::new(1, "Beam".to_string(), 1, ProductCategory::Oversized { zone_count: 12 });
+        assert!(matches!(
+            warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient),
+            Err(ModificationError::TooBig)
+        ));
+        
+        let product = AnyOldProduct::new(2, "Plank".to_string(), 1, ProductCategory::Oversized { zone_count: 11 });
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+        assert_eq!(warehouse.free_map().iter().next(), Some((0,1,0).into()..=(3,29,11).into()));
+    }
+
+    #[test]
+    fn test_move_product() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 10, ProductCategory::Normal);
+        let timestamp = product.timestamp();
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+
+        assert!(warehouse.move_product((0,0,0).into(), (2,1,3).into()).is_ok());
+        assert!(matches!(warehouse.get_product_ref(&(0,0,0).into()), Ok(WarehouseEntry::None)));
+        assert_eq!(warehouse.get_product_ref(&(2,1,3).into()).unwrap().expect_ref("Moved").timestamp(), timestamp);
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![(2,1,3).into()]));
+        assert_eq!(warehouse.search_by_name("Bolts"), Some(&vec![(2,1,3).into()]));
+
+        let mut free = warehouse.free_map().iter();
+        assert_eq!(free.next(), Some((0,0,0).into()..=(2,1,2).into()));
+        assert_eq!(free.next(), Some((2,2,0).into()..=(3,3,3).into()));
+        assert_eq!(free.next(), None);
+    }
+
+    #[test]
+    fn test_move_oversized_within_span() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        let product = AnyOldProduct::new(1, "Beam".to_string(), 1, ProductCategory::Oversized { zone_count: 2 });
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+
+        assert!(warehouse.move_product((0,0,0).into(), (0,0,1).into()).is_ok());
+        assert!(matches!(warehouse.get_product_ref(&(0,0,0).into()), Ok(WarehouseEntry::None)));
+        assert!(matches!(warehouse.get_product_ref(&(0,0,1).into()), Ok(WarehouseEntry::Some(_))));
+        assert!(matches!(warehouse.get_product_ref(&(0,0,3).into()), Ok(WarehouseEntry::OversizedPlaceholder)));
+        assert!(matches!(warehouse.move_product((0,0,1).into(), (0,1,2).into()), Err(ModificationError::InvalidCoords)));
+        assert!(matches!(warehouse.move_product((0,0,2).into(), (1,0,0).into()), Err(ModificationError::Placeholder)));
+
+        let mut free = warehouse.free_map().iter();
+        assert_eq!(free.next(), Some((0,0,0).into()..=(0,0,0).into()));
+        assert_eq!(free.next(), Some((0,1,0).into()..=(3,3,3).into()));
+    }
+
+    #[test]
+    fn test_move_failure_keeps_product() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        let product = AnyOldProduct::new(1, "Milk".to_string(), 1, ProductCategory::Fragile { expiry_date, max_row: 1 });
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+        let product = AnyOldProduct::new(2, "Bolts".to_string(), 1, ProductCategory::Normal);
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+
+        assert!(matches!(warehouse.move_product((0,0,0).into(), (2,0,0).into()), Err(ModificationError::Fragile)));
+        assert!(matches!(warehouse.move_product((0,0,0).into(), (0,0,1).into()), Err(ModificationError::Occupied)));
+        assert!(matches!(warehouse.move_product((0,0,0).into(), (9,0,0).into()), Err(ModificationError::InvalidCoords)));
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![(0,0,0).into()]));
+        assert_eq!(warehouse.search_expiry_dates(..).count(), 1);
+        assert_eq!(warehouse.free_map().iter().next(), Some((0,0,2).into()..=(3,3,3).into()));
+    }
+
+    #[test]
+    fn test_pick_and_restock() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 50, ProductCategory::Normal);
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+        let coords: StoreCoords = (0,0,0).into();
+
+        assert_eq!(warehouse.pick(&coords, 3).ok(), Some(47));
+        assert!(matches!(warehouse.pick(&coords, 48), Err(ModificationError::InsufficientAmount { available: 47 })));
+        assert!(matches!(warehouse.pick(&coords, 0), Err(ModificationError::InvalidAmount)));
+        assert_eq!(warehouse.restock(&coords, 3).ok(), Some(50));
+        assert_eq!(warehouse.quantity_by_id(&1), Some(50));
+
+        assert_eq!(warehouse.pick(&coords, 50).ok(), Some(0));
+        assert!(matches!(warehouse.get_product_ref(&coords), Ok(WarehouseEntry::None)));
+        assert_eq!(warehouse.search_by_id(&1), None);
+        assert_eq!(warehouse.quantity_by_id(&1), None);
+        assert_eq!(warehouse.free_map().iter().next(), Some((0,0,0).into()..=(3,3,3).into()));
+    }
+
+    #[test]
+    fn test_split_stack() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(4, 4, 4));
+        let product = AnyOldProduct::new(1, "Bolts".to_string(), 50, ProductCategory::Normal);
+        let timestamp = product.timestamp();
+        assert!(warehouse.add_product(product, &mut WarehouseAllocatorClosestFirstEfficient).is_ok());
+        let coords: StoreCoords = (0,0,0).into();
+
+        assert!(matches!(warehouse.split_stack(&coords, 50, &mut WarehouseAllocatorClosestFirstEfficient), Err(ModificationError::InsufficientAmount { .. })));
+        let new_coords = warehouse.split_stack(&coords, 20, &mut WarehouseAllocatorClosestFirstEfficient).ok();
+        assert_eq!(new_coords, Some((0,0,1).into()));
+
+        let split = warehouse.get_product_ref(&(0,0,1).into()).unwrap().expect_ref("Split stack");
+        assert_eq!(split.amount(), 20);
+        assert_eq!(split.timestamp(), timestamp);
+        assert_eq!(warehouse.get_product_ref(&coords).unwrap().expect_ref("Original stack").amount(), 30);
+        assert_eq!(warehouse.search_by_id(&1).map(Vec::len), Some(2));
+        assert_eq!(warehouse.quantity_by_id(&1), Some(50));
+    }
+
+    #[test]
+    fn test_out_of_range_coords() {
+        let mut warehouse = Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(2, 3, 4));
+        assert!(matches!(warehouse.get_product_ref(&(2,0,0).into()), Err(ModificationError::InvalidCoords)));
+        assert!(matches!(warehouse.get_product_ref(&(0,0,4).into()), Err(ModificationError::InvalidCoords)));
+        assert!(matches!(warehouse.remove_product((0,3,0).into()), Err(ModificationError::InvalidCoords)));
+        assert!(matches!(warehouse.pick(&(5,5,5).into(), 1), Err(ModificationError::InvalidCoords)));
+    }
+
+    #[test]
+    fn test_corrupt_snapshot() {
+        let snapshot = "{\n  \"dimensions\": {\"rows\": 1, \"shelves\": 1, \"zones\": 1},\n  \"store\": [[[\"None\"]]],\n  oops\n}";
+        match Warehouse::<AnyOldProduct>::from_json(&mut snapshot.as_bytes()) {
+            Err(SnapshotError::Corrupt { line, column, .. }) => assert_eq!((line, column), (4, 3)),
+            other => panic!("Expected a corrupt snapshot error, got {:?}", other.err()),
+        }
+
+        let mut buffer = Vec::new();
+        Warehouse::<AnyOldProduct>::new(WarehouseDimensions::new(1, 1, 1)).to_json(&mut buffer).unwrap();
+        let truncated = &buffer[..buffer.len() / 2];
+        assert!(matches!(Warehouse::<AnyOldProduct>::from_json(&mut &truncated[..]), Err(SnapshotError::Corrupt { .. })));
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::ops::{RangeBounds, RangeInclusive};
+use std::str::FromStr;
+use serde::Serializer;
+use serde_derive::Serialize;
+use time::Date;
+use crate::coords::StoreCoords;
+use super::{ModificationError, Product, ProductCategory, Warehouse};
+
+/// What to do with the expired stacks found by a sweep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SweepAction {
+    Remove,
+    /// Moves each stack to the first free place inside the zones between both coordinates, in row-major order
+    /// Stacks already inside the area are left alone
+    Quarantine(RangeInclusive<StoreCoords>),
+}
+
+/// A stack of a Fragile product, as listed by sweeps and expiry reports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FragileStack {
+    pub identifier: i64,
+    pub name: String,
+    pub amount: u64,
+    #[serde(serialize_with = "iso_date")]
+    pub expiry_date: Date,
+    pub coords: StoreCoords,
+}
+
+/// An expired stack removed by a sweep, or where it was sent when quarantined
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SweptStack {
+    #[serde(flatten)]
+    pub stack: FragileStack,
+    pub quarantined_at: Option<StoreCoords>,
+}
+
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub swept: Vec<SweptStack>,
+    /// Stacks left in place, because the quarantine area had no room for them
+    pub failed: Vec<(FragileStack, ModificationError)>,
+}
+
+impl SweepReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    pub fn total_amount(&self) -> u64 {
+        self.swept.iter().map(|s| s.stack.amount).sum()
+    }
+}
+
+/// Upper bounds of the expiry report buckets, in days from the reference date
+/// With bounds 3, 7 and 30 the buckets are expired, 0-3 days, 4-7 days, 8-30 days and later
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiryHorizons(Vec<u32>);
+
+impl ExpiryHorizons {
+    /// Bounds must be strictly increasing
+    pub fn new(bounds: Vec<u32>) -> Option<Self> {
+        bounds.is_sorted_by(|a, b| a < b).then_some(ExpiryHorizons(bounds))
+    }
+
+    // Bucket 0 is for expired stacks, the last one for everything past the last bound
+    fn bucket(&self, days_left: i64) -> usize {
+        if days_left < 0 {
+            return 0;
+        }
+        self.0.iter().position(|bound| days_left <= i64::from(*bound)).unwrap_or(self.0.len()) + 1
+    }
+
+    fn empty_buckets(&self) -> Vec<ExpiryBucket> {
+        let mut buckets = vec![ExpiryBucket::new("expired".to_string(), None, Some(-1))];
+        let mut from = 0;
+        for bound in &self.0 {
+            let to = i64::from(*bound);
+            buckets.push(ExpiryBucket::new(format!("{}-{} days", from, to), Some(from), Some(to)));
+            from = to + 1;
+        }
+        buckets.push(ExpiryBucket::new("later".to_string(), Some(from), None));
+        buckets
+    }
+}
+
+impl Default for ExpiryHorizons {
+    fn default() -> Self {
+        ExpiryHorizons(vec![3, 7, 30])
+    }
+}
+
+// Comma separated days, as in "3,7,30"
+impl FromStr for ExpiryHorizons {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s.split(',')
+            .map(|bound| bound.trim().parse().map_err(|_| "Expected days separated by commas"))
+            .collect::<Result<Vec<u32>, _>>()?;
+        ExpiryHorizons::new(bounds).ok_or("Days must be increasing")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiryBucket {
+    pub label: String,
+    /// Days left until expiry covered by the bucket, open ended when None
+    pub from_days: Option<i64>,
+    pub to_days: Option<i64>,
+    /// Stacks by row, each row sorted by expiry date and then location
+    pub rows: BTreeMap<usize, Vec<FragileStack>>,
+}
+
+impl ExpiryBucket {
+    fn new(label: String, from_days: Option<i64>, to_days: Option<i64>) -> Self {
+        ExpiryBucket { label, from_days, to_days, rows: BTreeMap::new() }
+    }
+
+    pub fn stacks(&self) -> impl Iterator<Item = &FragileStack> {
+        self.rows.values().flatten()
+    }
+
+    pub fn total_amount(&self) -> u64 {
+        self.stacks().map(|s| s.amount).sum()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiryReport {
+    #[serde(serialize_with = "iso_date")]
+    pub as_of: Date,
+    pub buckets: Vec<ExpiryBucket>,
+}
+
+impl ExpiryReport {
+    pub fn days_left(&self, stack: &FragileStack) -> i64 {
+        (stack.expiry_date - self.as_of).whole_days()
+    }
+}
+
+// Dates are written as YYYY-MM-DD, rather than the default year and ordinal pair
+fn iso_date<S: Serializer>(date: &Date, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(date)
+}
+
+impl<I: Product> Warehouse<I> {
+    /// Every Fragile stack expiring inside the range, soonest first
+    pub fn fragile_stacks(&self, range: impl RangeBounds<Date>) -> Vec<FragileStack> {
+        let mut identifiers: Vec<i64> = self.store_index_expiry_dates.range((range.start_bound().cloned(), range.end_bound().cloned()))
+            .flat_map(|(_, identifiers)| identifiers.iter().copied())
+            .collect();
+        identifiers.sort();
+        identifiers.dedup();
+
+        let mut stacks: Vec<FragileStack> = identifiers.iter()
+            .flat_map(|identifier| self.store_index_by_id.get(identifier).into_iter().flatten())
+            .filter_map(|coords| {
+                let product = self.entry(coords).expect_ref("Only Some values in map");
+                match product.quality() {
+                    // An identifier may have stacks with other expiry dates
+                    ProductCategory::Fragile { expiry_date, .. } if range.contains(expiry_date) => Some(FragileStack {
+                        identifier: *product.identifier(),
+                        name: product.name().clone(),
+                        amount: product.amount(),
+                        expiry_date: *expiry_date,
+                        coords: coords.clone(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        stacks.sort_by(|a, b| a.expiry_date.cmp(&b.expiry_date).then_with(|| a.coords.cmp(&b.coords)));
+        stacks
+    }
+
+    /// Removes or quarantines every Fragile stack that expired before the given date
+    /// Each change is recorded as usual, so running it inside a transaction makes the whole sweep undoable
+    pub fn sweep_expired(&mut self, as_of: Date, action: &SweepAction) -> SweepReport {
+        let mut report = SweepReport::default();
+
+        for stack in self.fragile_stacks(..as_of) {
+            let result = match action {
+                SweepAction::Remove => self.remove_product(stack.coords.clone()).map(|()| None),
+                SweepAction::Quarantine(area) if area.contains(&stack.coords) => continue,
+                SweepAction::Quarantine(area) => match self.quarantine_spot(&stack.coords, area) {
+                    Some(to) => self.move_product(stack.coords.clone(), to.clone()).map(|()| Some(to)),
+                    None => Err(ModificationError::Full),
+                },
+            };
+            match result {
+                Ok(quarantined_at) => report.swept.push(SweptStack { stack, quarantined_at }),
+                Err(e) => report.failed.push((stack, e)),
+            }
+        }
+
+        report
+    }
+
+    /// Every Fragile stack in stock, sorted into the buckets by the days left until it expires
+    pub fn expiry_report(&self, as_of: Date, horizons: &ExpiryHorizons) -> ExpiryReport {
+        let mut buckets = horizons.empty_buckets();
+        for stack in self.fragile_stacks(..) {
+            let bucket = horizons.bucket((stack.expiry_date - as_of).whole_days());
+            buckets[bucket].rows.entry(stack.coords.0).or_default().push(stack);
+        }
+        ExpiryReport { as_of, buckets }
+    }
+
+    // First place inside the area where the whole product fits
+    fn quarantine_spot(&self, coords: &StoreCoords, area: &RangeInclusive<StoreCoords>) -> Option<StoreCoords> {
+        let product = self.product_ref(coords).ok()?;
+        let extra_zones = Self::extra_zones(product);
+        let dimensions = self.dimensions;
+
+        self.free_map.iter_from(area.start().clone())
+            .take_while(|free| free.start() <= area.end())
+            .flat_map(|free| {
+                let start = free.start().max(area.start()).clone();
+                let end = free.end().min(area.end()).clone();
+                std::iter::successors(Some(start), move |c| c.next(&dimensions))
+                    .take_while(move |c| *c <= end)
+            })
+            .find(|c| StoreCoords(c.0, c.1, c.2 + extra_zones) <= *area.end() && self.check_placement(product, c).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::history::History;
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    fn date(day: u8) -> Date {
+        Date::from_calendar_date(2030, time::Month::January, day).unwrap()
+    }
+
+    fn stocked() -> Warehouse<AnyOldProduct> {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        let products = [
+            AnyOldProduct::new(1, "Milk".to_string(), 12, ProductCategory::Fragile { expiry_date: date(5), max_row: 1 }),
+            AnyOldProduct::new(2, "Bolts".to_string(), 100, ProductCategory::Normal),
+            AnyOldProduct::new(3, "Yogurt".to_string(), 6, ProductCategory::Fragile { expiry_date: date(2), max_row: 1 }),
+            AnyOldProduct::new(1, "Milk".to_string(), 4, ProductCategory::Fragile { expiry_date: date(20), max_row: 1 }),
+            AnyOldProduct::new(4, "Eggs".to_string(), 30, ProductCategory::Fragile { expiry_date: date(10), max_row: 0 }),
+        ];
+        for product in products {
+            warehouse.add_product(product, &mut allocator).unwrap();
+        }
+        warehouse
+    }
+
+    #[test]
+    fn test_sweep_removes_expired() {
+        let mut warehouse = stocked();
+        let mut history = History::new(5);
+        let report = history.run(&mut warehouse, |w| Ok::<_, ModificationError>(w.sweep_expired(date(10), &SweepAction::Remove))).unwrap();
+
+        assert!(report.is_ok());
+        let swept: Vec<_> = report.swept.iter().map(|s| (s.stack.identifier, s.stack.coords.clone())).collect();
+        assert_eq!(swept, vec![(3, StoreCoords(0, 0, 2)), (1, StoreCoords(0, 0, 0))]);
+        assert_eq!(report.total_amount(), 18);
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![StoreCoords(0, 0, 3)]));
+        assert!(warehouse.free_map().is_free(&StoreCoords(0, 0, 0)));
+        assert!(warehouse.free_map().is_free(&StoreCoords(0, 0, 2)));
+        assert!(warehouse.verify().is_ok());
+
+        history.undo(&mut warehouse).unwrap();
+        assert_eq!(warehouse.search_by_id(&3), Some(&vec![StoreCoords(0, 0, 2)]));
+    }
+
+    #[test]
+    fn test_sweep_quarantines_expired() {
+        let mut warehouse = stocked();
+        let area = StoreCoords(0, 1, 2)..=StoreCoords(1, 0, 0);
+        let report = warehouse.sweep_expired(date(11), &SweepAction::Quarantine(area.clone()));
+
+        let moved: Vec<_> = report.swept.iter().map(|s| (s.stack.identifier, s.quarantined_at.clone())).collect();
+        assert_eq!(moved, vec![(3, Some(StoreCoords(0, 1, 2))), (1, Some(StoreCoords(0, 1, 3)))]);
+        // Eggs cannot go above row 0, and the only place left on row 0 is taken
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0.identifier, 4);
+        assert!(matches!(report.failed[0].1, ModificationError::Full));
+        assert_eq!(warehouse.search_by_id(&4), Some(&vec![StoreCoords(0, 1, 0)]));
+        assert!(warehouse.verify().is_ok());
+
+        // Stacks already in quarantine are not moved again
+        let again = warehouse.sweep_expired(date(11), &SweepAction::Quarantine(area));
+        assert!(again.swept.is_empty());
+        assert_eq!(again.failed.len(), 1);
+    }
+
+    #[test]
+    fn test_expiry_report_buckets() {
+        let warehouse = stocked();
+        let report = warehouse.expiry_report(date(3), &ExpiryHorizons::default());
+
+        let labels: Vec<_> = report.buckets.iter().map(|b| b.label.as_str()).collect();
+        assert_eq!(labels, vec!["expired", "0-3 days", "4-7 days", "8-30 days", "later"]);
+        let identifiers: Vec<Vec<i64>> = report.buckets.iter().map(|b| b.stacks().map(|s| s.identifier).collect()).collect();
+        // Milk expires in 2 days at 0,0,0 and in 17 days at 0,0,3, eggs in 7 days
+        assert_eq!(identifiers, vec![vec![3], vec![1], vec![4], vec![1], vec![]]);
+        assert_eq!(report.buckets[3].rows.keys().collect::<Vec<_>>(), vec![&0]);
+        assert_eq!(report.buckets[1].total_amount(), 12);
+        assert_eq!(report.days_left(&report.buckets[0].rows[&0][0]), -1);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["as_of"], "2030-01-03");
+        assert_eq!(json["buckets"][2]["rows"]["0"][0]["expiry_date"], "2030-01-10");
+        assert_eq!(json["buckets"][4]["from_days"], 31);
+
+        let horizons: ExpiryHorizons = "1, 20".parse().unwrap();
+        let report = warehouse.expiry_report(date(3), &horizons);
+        assert_eq!(report.buckets.iter().map(|b| b.stacks().count()).collect::<Vec<_>>(), vec![1, 0, 3, 0]);
+        assert!("7,3".parse::<ExpiryHorizons>().is_err());
+        assert!("soon".parse::<ExpiryHorizons>().is_err());
+    }
+}
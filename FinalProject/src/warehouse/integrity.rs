@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use crate::coords::StoreCoords;
+use crate::free_map::FreeMap;
+use super::{Product, ProductCategory, Warehouse, WarehouseEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    Name,
+    Identifier,
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The grid does not have the size given by the dimensions, nothing else is checked
+    ShapeMismatch,
+    IndexedEmptyZone {
+        index: IndexKind,
+        coords: StoreCoords,
+    },
+    IndexedWrongProduct {
+        index: IndexKind,
+        coords: StoreCoords,
+    },
+    MissingIndexEntry {
+        index: IndexKind,
+        coords: StoreCoords,
+    },
+    ExpiryIndexMismatch {
+        date: time::Date,
+        identifier: i64,
+    },
+    OrphanPlaceholder {
+        coords: StoreCoords,
+    },
+    MissingPlaceholder {
+        coords: StoreCoords,
+    },
+    OversizedOutOfShelf {
+        coords: StoreCoords,
+    },
+    OccupiedMarkedFree {
+        coords: StoreCoords,
+    },
+    EmptyMarkedOccupied {
+        coords: StoreCoords,
+    },
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inconsistency::ShapeMismatch => write!(f, "Store grid does not match the warehouse dimensions"),
+            Inconsistency::IndexedEmptyZone { index, coords } => write!(f, "{:?} index points to empty zone {:?}", index, coords),
+            Inconsistency::IndexedWrongProduct { index, coords } => write!(f, "{:?} index entry does not match the product at {:?}", index, coords),
+            Inconsistency::MissingIndexEntry { index, coords } => write!(f, "Product at {:?} is missing from the {:?} index", coords, index),
+            Inconsistency::ExpiryIndexMismatch { date, identifier } => write!(f, "Expiry index for {} disagrees on identifier {}", date, identifier),
+            Inconsistency::OrphanPlaceholder { coords } => write!(f, "Placeholder at {:?} has no owning Oversized product", coords),
+            Inconsistency::MissingPlaceholder { coords } => write!(f, "Zone {:?} should be an Oversized placeholder", coords),
+            Inconsistency::OversizedOutOfShelf { coords } => write!(f, "Oversized product at {:?} extends past the end of the shelf", coords),
+            Inconsistency::OccupiedMarkedFree { coords } => write!(f, "Free map covers occupied zone {:?}", coords),
+            Inconsistency::EmptyMarkedOccupied { coords } => write!(f, "Free map is missing empty zone {:?}", coords),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub issues: Vec<Inconsistency>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+// Indices as they should be according to the grid, in row major order
+#[derive(Default)]
+struct DerivedIndices {
+    by_name: BTreeMap<String, Vec<StoreCoords>>,
+    by_id: BTreeMap<i64, Vec<StoreCoords>>,
+    expiry_dates: BTreeMap<time::Date, Vec<i64>>,
+    timestamps: BTreeMap<time::UtcDateTime, Vec<StoreCoords>>,
+}
+
+impl<I: Product> Warehouse<I> {
+    /// Checks every derived structure against the store grid
+    pub fn verify(&self) -> IntegrityReport {
+        let mut report = IntegrityReport::default();
+        if !self.shape_matches() {
+            report.issues.push(Inconsistency::ShapeMismatch);
+            return report;
+        }
+
+        self.verify_placeholders(&mut report.issues);
+
+        let derived = self.derive_indices();
+        self.compare_index(&self.store_index_by_name, &derived.by_name, IndexKind::Name, &mut report.issues);
+        self.compare_index(&self.store_index_by_id, &derived.by_id, IndexKind::Identifier, &mut report.issues);
+        compare_expiry_index(&self.store_index_expiry_dates, &derived.expiry_dates, &mut report.issues);
+        self.compare_index(&self.store_index_timestamps, &derived.timestamps, IndexKind::Timestamp, &mut report.issues);
+
+        for coords in self.all_coords() {
+            let occupied = !matches!(self.entry(&coords), WarehouseEntry::None);
+            match (occupied, self.free_map.is_free(&coords)) {
+                (true, true) => report.issues.push(Inconsistency::OccupiedMarkedFree { coords }),
+                (false, false) => report.issues.push(Inconsistency::EmptyMarkedOccupied { coords }),
+                _ => {}
+            }
+        }
+
+        report
+    }
+
+    /// Rebuilds placeholders, indices and the free map from the products in the grid
+    /// Index entries end up in row major order, the original insertion order is lost
+    /// Returns false if the grid itself does not match the dimensions, in which case nothing is changed
+    pub fn rebuild_indices(&mut self) -> bool {
+        if !self.shape_matches() {
+            return false;
+        }
+
+        for shelf in self.store.iter_mut().flatten() {
+            let mut span_left = 0;
+            for entry in shelf.iter_mut() {
+                match entry {
+                    WarehouseEntry::Some(product) => {
+                        span_left = Self::extra_zones(product);
+                    }
+                    WarehouseEntry::OversizedPlaceholder if span_left == 0 => {
+                        *entry = WarehouseEntry::None;
+                    }
+                    WarehouseEntry::None if span_left > 0 => {
+                        *entry = WarehouseEntry::OversizedPlaceholder;
+                        span_left -= 1;
+                    }
+                    _ => {
+                        span_left = span_left.saturating_sub(1);
+                    }
+                }
+            }
+        }
+
+        let derived = self.derive_indices();
+        self.store_index_by_name = derived.by_name;
+        self.store_index_by_id = derived.by_id;
+        self.store_index_expiry_dates = derived.expiry_dates;
+        self.store_index_timestamps = derived.timestamps;
+
+        let mut free_map = FreeMap::new(self.dimensions);
+        for coords in self.all_coords() {
+            if !matches!(self.entry(&coords), WarehouseEntry::None) {
+                free_map.occupy_single(coords);
+            }
+        }
+        self.free_map = free_map;
+        true
+    }
+
+    // The timestamp index is not saved in snapshots, so it is derived again after loading one
+    pub(super) fn rebuild_timestamp_index(&mut self) {
+        if self.shape_matches() {
+            self.store_index_timestamps = self.derive_indices().timestamps;
+        }
+    }
+
+    fn shape_matches(&self) -> bool {
+        self.store.len() == self.dimensions.rows
+            && self.store.iter().all(|row| row.len() == self.dimensions.shelves)
+            && self.store.iter().flatten().all(|shelf| shelf.len() == self.dimensions.zones)
+    }
+
+    fn all_coords(&self) -> impl Iterator<Item = StoreCoords> + use<I> {
+        let dimensions = self.dimensions;
+        (0..dimensions.rows).flat_map(move |row| {
+            (0..dimensions.shelves).flat_map(move |shelf| {
+                (0..dimensions.zones).map(move |zone| StoreCoords(row, shelf, zone))
+            })
+        })
+    }
+
+    fn verify_placeholders(&self, issues: &mut Vec<Inconsistency>) {
+        for (row, shelves) in self.store.iter().enumerate() {
+            for (shelf, zones) in shelves.iter().enumerate() {
+                let mut span_left = 0;
+                for (zone, entry) in zones.iter().enumerate() {
+                    let coords = StoreCoords(row, shelf, zone);
+                    match entry {
+                        WarehouseEntry::Some(product) => {
+                            if span_left > 0 {
+                                issues.push(Inconsistency::MissingPlaceholder { coords: coords.clone() });
+                            }
+                            span_left = Self::extra_zones(product);
+                            if zone + span_left >= zones.len() {
+                                issues.push(Inconsistency::OversizedOutOfShelf { coords });
+                            }
+                        }
+                        WarehouseEntry::OversizedPlaceholder => {
+                            if span_left == 0 {
+                                issues.push(Inconsistency::OrphanPlaceholder { coords });
+                            } else {
+                                span_left -= 1;
+                            }
+                        }
+                        WarehouseEntry::None => {
+                            if span_left > 0 {
+                                issues.push(Inconsistency::MissingPlaceholder { coords });
+                                span_left -= 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn derive_indices(&self) -> DerivedIndices {
+        let mut derived = DerivedIndices::default();
+        for coords in self.all_coords() {
+            if let WarehouseEntry::Some(product) = self.entry(&coords) {
+                derived.by_name.entry(product.name().clone()).or_default().push(coords.clone());
+                derived.timestamps.entry(product.timestamp()).or_default().push(coords.clone());
+                derived.by_id.entry(*product.identifier()).or_default().push(coords);
+                if let ProductCategory::Fragile { expiry_date, .. } = product.quality() {
+                    derived.expiry_dates.entry(*expiry_date).or_default().push(*product.identifier());
+                }
+            }
+        }
+        derived
+    }
+
+    fn compare_index<K: Ord>(
+        &self,
+        actual: &BTreeMap<K, Vec<StoreCoords>>,
+        expected: &BTreeMap<K, Vec<StoreCoords>>,
+        index: IndexKind,
+        issues: &mut Vec<Inconsistency>,
+    ) {
+        let mut unmatched: BTreeMap<&K, Vec<&StoreCoords>> = expected.iter()
+            .map(|(key, coords)| (key, coords.iter().collect()))
+            .collect();
+
+        for (key, coords) in actual {
+            for c in coords {
+                let expected_coords = unmatched.get_mut(key);
+                if let Some(position) = expected_coords.as_ref().and_then(|e| e.iter().position(|x| *x == c)) {
+                    expected_coords.expect("Position was found").remove(position);
+                    continue;
+                }
+
+                let occupied = self.get_product_ref(c).is_ok_and(WarehouseEntry::is_some);
+                issues.push(if occupied {
+                    Inconsistency::IndexedWrongProduct { index, coords: c.clone() }
+                } else {
+                    Inconsistency::IndexedEmptyZone { index, coords: c.clone() }
+                });
+            }
+        }
+
+        for coords in unmatched.into_values().flatten() {
+            issues.push(Inconsistency::MissingIndexEntry { index, coords: coords.clone() });
+        }
+    }
+}
+
+fn compare_expiry_index(actual: &BTreeMap<time::Date, Vec<i64>>, expected: &BTreeMap<time::Date, Vec<i64>>, issues: &mut Vec<Inconsistency>) {
+    let mut unmatched = expected.clone();
+    for (date, identifiers) in actual {
+        for identifier in identifiers {
+            let expected_identifiers = unmatched.get_mut(date);
+            if let Some(position) = expected_identifiers.as_ref().and_then(|e| e.iter().position(|x| x == identifier)) {
+                expected_identifiers.expect("Position was found").remove(position);
+            } else {
+                issues.push(Inconsistency::ExpiryIndexMismatch { date: *date, identifier: *identifier });
+            }
+        }
+    }
+
+    for (date, identifiers) in unmatched {
+        for identifier in identifiers {
+            issues.push(Inconsistency::ExpiryIndexMismatch { date, identifier });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    fn filled_warehouse() -> Warehouse<AnyOldProduct> {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap();
+        for (identifier, name, quality) in [
+            (1, "Bolts", ProductCategory::Normal),
+            (2, "Beam", ProductCategory::Oversized { zone_count: 2 }),
+            (3, "Milk", ProductCategory::Fragile { expiry_date, max_row: 1 }),
+        ] {
+            let product = AnyOldProduct::new(identifier, name.to_string(), 10, quality);
+            assert!(warehouse.add_product(product, &mut allocator).is_ok());
+        }
+        warehouse
+    }
+
+    #[test]
+    fn test_consistent_warehouse() {
+        let warehouse = filled_warehouse();
+        assert!(warehouse.verify().is_ok());
+    }
+
+    #[test]
+    fn test_detect_and_repair() {
+        let mut warehouse = filled_warehouse();
+        warehouse.store[0][0][0] = WarehouseEntry::None;
+        warehouse.store[0][0][3] = WarehouseEntry::None;
+        warehouse.store[1][1][3] = WarehouseEntry::OversizedPlaceholder;
+        warehouse.store_index_expiry_dates.clear();
+
+        let issues = warehouse.verify().issues;
+        assert!(issues.contains(&Inconsistency::IndexedEmptyZone { index: IndexKind::Name, coords: (0,0,0).into() }));
+        assert!(issues.contains(&Inconsistency::IndexedEmptyZone { index: IndexKind::Identifier, coords: (0,0,0).into() }));
+        assert!(issues.contains(&Inconsistency::EmptyMarkedOccupied { coords: (0,0,0).into() }));
+        assert!(issues.contains(&Inconsistency::MissingPlaceholder { coords: (0,0,3).into() }));
+        assert!(issues.contains(&Inconsistency::OrphanPlaceholder { coords: (1,1,3).into() }));
+        assert!(issues.contains(&Inconsistency::OccupiedMarkedFree { coords: (1,1,3).into() }));
+        assert!(issues.contains(&Inconsistency::ExpiryIndexMismatch {
+            date: time::Date::from_calendar_date(2030, time::Month::January, 1).unwrap(),
+            identifier: 3,
+        }));
+
+        assert!(warehouse.rebuild_indices());
+        assert!(warehouse.verify().is_ok());
+        assert_eq!(warehouse.search_by_id(&1), None);
+        assert!(matches!(warehouse.get_product_ref(&(0,0,3).into()), Ok(WarehouseEntry::OversizedPlaceholder)));
+        assert!(matches!(warehouse.get_product_ref(&(1,1,3).into()), Ok(WarehouseEntry::None)));
+        assert_eq!(warehouse.search_expiry_dates(..).count(), 1);
+    }
+
+    #[test]
+    fn test_shape_mismatch() {
+        let mut warehouse = filled_warehouse();
+        warehouse.store[1].pop();
+        assert_eq!(warehouse.verify().issues, vec![Inconsistency::ShapeMismatch]);
+        assert!(!warehouse.rebuild_indices());
+    }
+
+    #[test]
+    fn test_from_json_repairs_indices() {
+        let warehouse = filled_warehouse();
+        let mut buffer = Vec::new();
+        warehouse.to_json(&mut buffer).unwrap();
+
+        let mut snapshot: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        snapshot["warehouse"]["store_index_by_name"] = serde_json::json!({ "Ghost": [[1, 1, 1]] });
+        let buffer = serde_json::to_vec(&snapshot).unwrap();
+
+        let (restored, report) = Warehouse::<AnyOldProduct>::from_json(&mut buffer.as_slice()).unwrap();
+        assert!(report.issues.contains(&Inconsistency::IndexedEmptyZone { index: IndexKind::Name, coords: (1,1,1).into() }));
+        assert!(report.issues.contains(&Inconsistency::MissingIndexEntry { index: IndexKind::Name, coords: (0,0,0).into() }));
+        assert!(restored.verify().is_ok());
+        assert_eq!(restored.search_by_name("Bolts"), Some(&vec![StoreCoords(0,0,0)]));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use serde_derive::Serialize;
+use time::{Duration, UtcDateTime};
+use crate::coords::StoreCoords;
+use super::{ModificationError, Product, ProductCategory, Warehouse};
+
+/// Product to pick, by identifier or by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PickTarget {
+    Identifier(i64),
+    Name(String),
+}
+
+impl PickTarget {
+    /// Numbers are read as identifiers, anything else as a name
+    pub fn parse(value: &str) -> PickTarget {
+        match value.trim().parse() {
+            Ok(identifier) => PickTarget::Identifier(identifier),
+            Err(_) => PickTarget::Name(value.trim().to_string()),
+        }
+    }
+}
+
+impl Display for PickTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PickTarget::Identifier(identifier) => write!(f, "ID {}", identifier),
+            PickTarget::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickMode {
+    /// Only plans the picks, the warehouse is left untouched
+    DryRun,
+    /// Takes the units out, emptied stacks are removed
+    Commit,
+}
+
+/// Units to take from one stack, in the order they should be picked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PickLine {
+    pub coords: StoreCoords,
+    pub quantity: u64,
+}
+
+/// A stack that has been in the warehouse for a while, from the oldest stock report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgedStack {
+    pub identifier: i64,
+    pub name: String,
+    pub amount: u64,
+    pub stored_since: UtcDateTime,
+    pub age: Duration,
+    pub coords: StoreCoords,
+}
+
+impl<I: Product> Warehouse<I> {
+    /// Plans, and optionally takes, a quantity across the stacks of a product, first expired first out
+    /// Fragile stacks go first, soonest expiry first, then the rest in the order they were stored
+    /// Nothing is picked unless the whole quantity is available
+    pub fn pick_fefo(&mut self, target: &PickTarget, quantity: u64, mode: PickMode) -> Result<Vec<PickLine>, ModificationError> {
+        let stacks = self.target_stacks(target)?;
+        let identifiers: BTreeSet<i64> = stacks.iter()
+            .map(|coords| *self.entry(coords).expect_ref("Only Some values in map").identifier())
+            .collect();
+        let expiry = |coords: &StoreCoords| match self.entry(coords).expect_ref("Only Some values in map").quality() {
+            ProductCategory::Fragile { expiry_date, .. } => Some(*expiry_date),
+            _ => None,
+        };
+
+        // The expiry index gives the dates in order, only those listing one of the identifiers are looked at
+        // Stacks expiring on the same day keep their storage order
+        let fragile = stacks.iter().filter(|coords| expiry(coords).is_some()).count();
+        let mut ordered = Vec::with_capacity(stacks.len());
+        for (date, expiring) in &self.store_index_expiry_dates {
+            if ordered.len() == fragile {
+                break;
+            }
+            if expiring.iter().any(|identifier| identifiers.contains(identifier)) {
+                ordered.extend(stacks.iter().filter(|coords| expiry(coords) == Some(*date)).cloned());
+            }
+        }
+        ordered.extend(stacks.iter().filter(|coords| expiry(coords).is_none()).cloned());
+        self.pick_in_order(ordered, quantity, mode)
+    }
+
+    /// Same as pick_fefo, but always taking from the stacks that entered the warehouse first
+    pub fn pick_fifo(&mut self, target: &PickTarget, quantity: u64, mode: PickMode) -> Result<Vec<PickLine>, ModificationError> {
+        let mut stacks = self.target_stacks(target)?;
+        stacks.sort_by_key(|coords| self.entry(coords).expect_ref("Only Some values in map").timestamp());
+        self.pick_in_order(stacks, quantity, mode)
+    }
+
+    /// Every stack stored for at least the given age, oldest first
+    pub fn oldest_stock(&self, as_of: UtcDateTime, min_age: Duration) -> Vec<AgedStack> {
+        let Some(cutoff) = as_of.checked_sub(min_age) else {
+            return Vec::new();
+        };
+        self.store_index_timestamps.range(..=cutoff)
+            .flat_map(|(timestamp, stacks)| stacks.iter().map(move |coords| (*timestamp, coords)))
+            .map(|(stored_since, coords)| {
+                let product = self.entry(coords).expect_ref("Only Some values in map");
+                AgedStack {
+                    identifier: *product.identifier(),
+                    name: product.name().clone(),
+                    amount: product.amount(),
+                    stored_since,
+                    age: as_of - stored_since,
+                    coords: coords.clone(),
+                }
+            })
+            .collect()
+    }
+
+    fn target_stacks(&self, target: &PickTarget) -> Result<Vec<StoreCoords>, ModificationError> {
+        let stacks = match target {
+            PickTarget::Identifier(identifier) => self.store_index_by_id.get(identifier),
+            PickTarget::Name(name) => self.store_index_by_name.get(name),
+        };
+        stacks.cloned().ok_or(ModificationError::NotFound)
+    }
+
+    // Takes from each stack in turn until the quantity is reached
+    fn pick_in_order(&mut self, stacks: Vec<StoreCoords>, quantity: u64, mode: PickMode) -> Result<Vec<PickLine>, ModificationError> {
+        if quantity == 0 {
+            return Err(ModificationError::InvalidAmount);
+        }
+
+        let mut plan = Vec::new();
+        let mut remaining = quantity;
+        for coords in stacks {
+            if remaining == 0 {
+                break;
+            }
+            let taken = self.entry(&coords).expect_ref("Only Some values in map").amount().min(remaining);
+            remaining -= taken;
+            plan.push(PickLine { coords, quantity: taken });
+        }
+        if remaining > 0 {
+            return Err(ModificationError::InsufficientAmount { available: quantity - remaining });
+        }
+
+        if mode == PickMode::Commit {
+            self.transaction(|w| plan.iter().try_for_each(|line| w.pick(&line.coords, line.quantity).map(|_| ())))?;
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    fn at(mut product: AnyOldProduct, day: u8) -> AnyOldProduct {
+        product.set_timestamp(UtcDateTime::new(time::Date::from_calendar_date(2025, time::Month::March, day).unwrap(), time::Time::MIDNIGHT));
+        product
+    }
+
+    fn milk(amount: u64, day: u8) -> AnyOldProduct {
+        let expiry_date = time::Date::from_calendar_date(2030, time::Month::January, day).unwrap();
+        AnyOldProduct::new(1, "Milk".to_string(), amount, ProductCategory::Fragile { expiry_date, max_row: 1 })
+    }
+
+    fn stocked() -> Warehouse<AnyOldProduct> {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for product in [milk(10, 20), milk(5, 3), AnyOldProduct::new(2, "Bolts".to_string(), 50, ProductCategory::Normal), milk(8, 9)] {
+            warehouse.add_product(product, &mut allocator).unwrap();
+        }
+        warehouse
+    }
+
+    #[test]
+    fn test_fefo_dry_run() {
+        let mut warehouse = stocked();
+        let plan = warehouse.pick_fefo(&PickTarget::Name("Milk".to_string()), 15, PickMode::DryRun).unwrap();
+        assert_eq!(plan, vec![
+            PickLine { coords: StoreCoords(0, 0, 1), quantity: 5 },
+            PickLine { coords: StoreCoords(0, 0, 3), quantity: 8 },
+            PickLine { coords: StoreCoords(0, 0, 0), quantity: 2 },
+        ]);
+        assert_eq!(warehouse.quantity_by_id(&1), Some(23));
+
+        assert!(matches!(warehouse.pick_fefo(&PickTarget::Identifier(1), 24, PickMode::DryRun), Err(ModificationError::InsufficientAmount { available: 23 })));
+        assert!(matches!(warehouse.pick_fefo(&PickTarget::Identifier(7), 1, PickMode::DryRun), Err(ModificationError::NotFound)));
+        assert!(matches!(warehouse.pick_fefo(&PickTarget::Identifier(1), 0, PickMode::DryRun), Err(ModificationError::InvalidAmount)));
+    }
+
+    #[test]
+    fn test_fefo_commit() {
+        let mut warehouse = stocked();
+        let plan = warehouse.pick_fefo(&PickTarget::parse("1"), 15, PickMode::Commit).unwrap();
+        assert_eq!(plan.len(), 3);
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![StoreCoords(0, 0, 0)]));
+        assert_eq!(warehouse.quantity_by_id(&1), Some(8));
+        assert_eq!(warehouse.search_expiry_dates(..).count(), 1);
+        assert!(warehouse.verify().is_ok());
+
+        let plan = warehouse.pick_fefo(&PickTarget::parse("Bolts"), 50, PickMode::Commit).unwrap();
+        assert_eq!(plan, vec![PickLine { coords: StoreCoords(0, 0, 2), quantity: 50 }]);
+        assert!(warehouse.search_by_name("Bolts").is_none());
+    }
+
+    #[test]
+    fn test_fifo_and_oldest_stock() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(2, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        let bolts = |amount| AnyOldProduct::new(2, "Bolts".to_string(), amount, ProductCategory::Normal);
+        for product in [at(bolts(10), 20), at(bolts(5), 3), at(milk(8, 9), 1), at(bolts(7), 10)] {
+            warehouse.add_product(product, &mut allocator).unwrap();
+        }
+
+        let plan = warehouse.pick_fifo(&PickTarget::Identifier(2), 8, PickMode::DryRun).unwrap();
+        assert_eq!(plan, vec![
+            PickLine { coords: StoreCoords(0, 0, 1), quantity: 5 },
+            PickLine { coords: StoreCoords(0, 0, 3), quantity: 3 },
+        ]);
+
+        let as_of = UtcDateTime::new(time::Date::from_calendar_date(2025, time::Month::March, 21).unwrap(), time::Time::MIDNIGHT);
+        let oldest: Vec<_> = warehouse.oldest_stock(as_of, Duration::days(11)).into_iter().map(|s| (s.identifier, s.coords, s.age.whole_days())).collect();
+        assert_eq!(oldest, vec![(1, StoreCoords(0, 0, 2), 20), (2, StoreCoords(0, 0, 1), 18), (2, StoreCoords(0, 0, 3), 11)]);
+
+        warehouse.pick_fifo(&PickTarget::Identifier(2), 8, PickMode::Commit).unwrap();
+        warehouse.move_product(StoreCoords(0, 0, 2), StoreCoords(1, 1, 1)).unwrap();
+        let oldest: Vec<_> = warehouse.oldest_stock(as_of, Duration::days(11)).into_iter().map(|s| (s.coords, s.amount)).collect();
+        assert_eq!(oldest, vec![(StoreCoords(1, 1, 1), 8), (StoreCoords(0, 0, 3), 4)]);
+        assert!(warehouse.verify().is_ok());
+    }
+}
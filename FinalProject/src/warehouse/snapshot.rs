@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use time::UtcDateTime;
+use crate::allocators::AnyAllocator;
+use crate::coords::WarehouseDimensions;
+use super::{IntegrityReport, Product, SnapshotError, Warehouse};
+
+/// Version written by to_json, older ones are migrated when loaded
+pub const SCHEMA_VERSION: u64 = 5;
+
+// Each function upgrades a snapshot by one version, starting from version 1
+// Snapshots before version 3 are a bare warehouse, without the envelope
+const MIGRATIONS: [fn(Value) -> Result<Value, SnapshotError>; 4] = [
+    migrate_v1_cubic_store,
+    migrate_v2_envelope,
+    migrate_v3_pick_counts,
+    migrate_v4_allocator,
+];
+
+#[derive(Serialize)]
+#[serde(bound(serialize = "I: Product"))]
+struct SnapshotRef<'a, I> {
+    schema_version: u64,
+    created: Option<UtcDateTime>,
+    dimensions: WarehouseDimensions,
+    warehouse: &'a Warehouse<I>,
+    // Kept out of the warehouse itself, replaying a journal rebuilds the same warehouse but not its pick history
+    pick_counts: &'a BTreeMap<i64, u64>,
+    allocator: Option<&'a AnyAllocator>,
+}
+
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I: Product"))]
+struct Snapshot<I> {
+    #[allow(unused)]
+    schema_version: u64,
+    /// Unknown for snapshots written before the envelope existed
+    #[allow(unused)]
+    created: Option<UtcDateTime>,
+    dimensions: WarehouseDimensions,
+    warehouse: Warehouse<I>,
+    /// Empty for snapshots written before picks were counted
+    pick_counts: BTreeMap<i64, u64>,
+    /// None for snapshots written before the allocator was saved
+    allocator: Option<AnyAllocator>,
+}
+
+impl<I: Product> Warehouse<I> {
+    pub fn to_json(&self, writer: &mut impl std::io::Write) -> Result<(), SnapshotError> {
+        let snapshot = SnapshotRef {
+            schema_version: SCHEMA_VERSION,
+            created: Some(UtcDateTime::now()),
+            dimensions: self.dimensions,
+            warehouse: self,
+            pick_counts: &self.pick_counts,
+            allocator: self.allocator.as_ref(),
+        };
+        serde_json::to_writer_pretty(writer, &snapshot).map_err(SnapshotError::from)
+    }
+
+    /// Loads a snapshot of any known version, checking it and rebuilding the derived structures if anything is off
+    /// The report lists what was found before the repair
+    pub fn from_json(reader: &mut impl std::io::BufRead) -> Result<(Self, IntegrityReport), SnapshotError> {
+        let mut value: Value = serde_json::from_reader(reader)?;
+        let version = schema_version(&value)?;
+        if version > SCHEMA_VERSION {
+            return Err(SnapshotError::VersionMismatch { found: version, supported: SCHEMA_VERSION });
+        }
+        for migration in &MIGRATIONS[version as usize - 1..] {
+            value = migration(value)?;
+        }
+
+        let snapshot: Snapshot<I> = serde_json::from_value(value).map_err(|e| SnapshotError::Invalid(e.to_string()))?;
+        let mut warehouse = snapshot.warehouse;
+        warehouse.pick_counts = snapshot.pick_counts;
+        warehouse.allocator = snapshot.allocator;
+        if snapshot.dimensions != warehouse.dimensions {
+            return Err(SnapshotError::Invalid(format!(
+                "envelope is for {} but the warehouse has {}", snapshot.dimensions, warehouse.dimensions
+            )));
+        }
+
+        warehouse.rebuild_timestamp_index();
+        let report = warehouse.verify();
+        if !report.is_ok() {
+            if !warehouse.rebuild_indices() {
+                return Err(SnapshotError::Unrepairable(report));
+            }
+            let remaining = warehouse.verify();
+            if !remaining.is_ok() {
+                return Err(SnapshotError::Unrepairable(remaining));
+            }
+        }
+        Ok((warehouse, report))
+    }
+}
+
+// Versions before the envelope are told apart by their fields
+fn schema_version(value: &Value) -> Result<u64, SnapshotError> {
+    let object = value.as_object().ok_or_else(|| SnapshotError::Invalid("expected an object".to_string()))?;
+    match object.get("schema_version") {
+        Some(version) => version.as_u64()
+            .filter(|v| *v > 0)
+            .ok_or_else(|| SnapshotError::Invalid(format!("invalid schema_version {}", version))),
+        None if object.contains_key("store_max_idx") => Ok(1),
+        None if object.contains_key("dimensions") => Ok(2),
+        None => Err(SnapshotError::Invalid("not a warehouse snapshot".to_string())),
+    }
+}
+
+fn take_field(object: &mut Map<String, Value>, name: &str) -> Result<Value, SnapshotError> {
+    object.remove(name).ok_or_else(|| SnapshotError::Invalid(format!("missing field {}", name)))
+}
+
+// The expiry index used to be written as a map, which only worked while it was empty
+fn expiry_index_as_pairs(object: &mut Map<String, Value>) -> Result<(), SnapshotError> {
+    if let Some(Value::Object(index)) = object.get("store_index_expiry_dates") {
+        if !index.is_empty() {
+            return Err(SnapshotError::Invalid("expiry index cannot be a map with entries".to_string()));
+        }
+        object.insert("store_index_expiry_dates".to_string(), json!([]));
+    }
+    Ok(())
+}
+
+// Version 1 stores were cubes with a single size, and had no persisted filters
+fn migrate_v1_cubic_store(mut value: Value) -> Result<Value, SnapshotError> {
+    let object = value.as_object_mut().expect("Version was read from an object");
+    let size = take_field(object, "store_max_idx")?;
+    let size = size.as_u64().filter(|s| *s > 0).ok_or_else(|| SnapshotError::Invalid(format!("invalid store_max_idx {}", size)))?;
+    let dimensions = json!({ "rows": size, "shelves": size, "zones": size });
+
+    object.insert("dimensions".to_string(), dimensions.clone());
+    object.insert("filters".to_string(), json!([]));
+    expiry_index_as_pairs(object)?;
+
+    let free_map = object.get_mut("free_map").and_then(Value::as_object_mut)
+        .ok_or_else(|| SnapshotError::Invalid("missing field free_map".to_string()))?;
+    take_field(free_map, "store_max_idx")?;
+    free_map.insert("dimensions".to_string(), dimensions.clone());
+    let ranges = free_map.get_mut("map").and_then(Value::as_array_mut)
+        .ok_or_else(|| SnapshotError::Invalid("missing field free_map.map".to_string()))?;
+    for bound in ranges.iter_mut().filter_map(Value::as_array_mut).flatten() {
+        let bound = bound.as_object_mut().ok_or_else(|| SnapshotError::Invalid("invalid free map range".to_string()))?;
+        take_field(bound, "max_idx")?;
+        bound.insert("dimensions".to_string(), dimensions.clone());
+    }
+
+    Ok(value)
+}
+
+// Version 2 is the bare warehouse, now wrapped in the envelope
+fn migrate_v2_envelope(mut value: Value) -> Result<Value, SnapshotError> {
+    let object = value.as_object_mut().expect("Version was read from an object");
+    expiry_index_as_pairs(object)?;
+    let dimensions = object.get("dimensions").cloned().expect("Version 2 was detected by this field");
+
+    Ok(json!({
+        "schema_version": 3,
+        "created": null,
+        "dimensions": dimensions,
+        "warehouse": value,
+    }))
+}
+
+// Picks were not counted before version 4
+fn migrate_v3_pick_counts(mut value: Value) -> Result<Value, SnapshotError> {
+    let object = value.as_object_mut().expect("Version was read from an object");
+    object.insert("schema_version".to_string(), json!(4));
+    object.insert("pick_counts".to_string(), json!({}));
+    Ok(value)
+}
+
+// The allocator was not saved before version 5
+fn migrate_v4_allocator(mut value: Value) -> Result<Value, SnapshotError> {
+    let object = value.as_object_mut().expect("Version was read from an object");
+    object.insert("schema_version".to_string(), json!(5));
+    object.insert("allocator".to_string(), Value::Null);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::StoreCoords;
+    use crate::filters::FilterRule;
+    use crate::AnyOldProduct;
+
+    const V1: &str = include_str!("../../fixtures/snapshot_v1.json");
+    const V2: &str = include_str!("../../fixtures/snapshot_v2.json");
+    const V3: &str = include_str!("../../fixtures/snapshot_v3.json");
+    const V4: &str = include_str!("../../fixtures/snapshot_v4.json");
+
+    fn load(snapshot: &str) -> Result<(Warehouse<AnyOldProduct>, IntegrityReport), SnapshotError> {
+        Warehouse::from_json(&mut snapshot.as_bytes())
+    }
+
+    #[test]
+    fn test_load_v1_fixture() {
+        let (warehouse, report) = load(V1).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(warehouse.dimensions(), WarehouseDimensions::new(3, 3, 3));
+        assert_eq!(warehouse.list_filters().len(), 0);
+        assert_eq!(warehouse.search_by_name("Beam"), Some(&vec![StoreCoords(0, 0, 0)]));
+        assert_eq!(warehouse.search_by_name("Nuts"), Some(&vec![StoreCoords(0, 1, 0)]));
+        assert_eq!(warehouse.search_by_name("Bolts"), None);
+        assert_eq!(warehouse.free_map().iter().next(), Some((0, 0, 2).into()..=(0, 0, 2).into()));
+    }
+
+    #[test]
+    fn test_load_v2_fixture() {
+        let (warehouse, report) = load(V2).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(warehouse.dimensions(), WarehouseDimensions::new(2, 3, 4));
+        let rules: Vec<_> = warehouse.list_filters().filter_map(|f| f.rule()).collect();
+        assert_eq!(rules, vec![FilterRule::UniqueNames, FilterRule::MinShelfLife { days: 3 }]);
+        assert_eq!(warehouse.search_by_name("Milk"), Some(&vec![StoreCoords(0, 1, 0)]));
+        assert_eq!(warehouse.search_expiry_dates(..).count(), 1);
+    }
+
+    #[test]
+    fn test_load_v3_fixture() {
+        let (warehouse, report) = load(V3).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(warehouse.dimensions(), WarehouseDimensions::new(2, 3, 4));
+        assert_eq!(warehouse.quantity_by_id(&1), Some(100));
+        assert_eq!(warehouse.pick_count(&1), 0);
+    }
+
+    #[test]
+    fn test_load_v4_fixture() {
+        let (warehouse, report) = load(V4).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(warehouse.quantity_by_id(&1), Some(100));
+        assert_eq!((warehouse.pick_count(&1), warehouse.pick_count(&5)), (3, 1));
+        assert_eq!(warehouse.allocator(), None);
+    }
+
+    #[test]
+    fn test_older_versions_upgrade_to_current() {
+        for fixture in [V1, V2, V3, V4] {
+            let (warehouse, _) = load(fixture).unwrap();
+            let mut buffer = Vec::new();
+            warehouse.to_json(&mut buffer).unwrap();
+            let written: Value = serde_json::from_slice(&buffer).unwrap();
+            assert_eq!(written["schema_version"], SCHEMA_VERSION);
+            assert_eq!(written["dimensions"], serde_json::to_value(warehouse.dimensions()).unwrap());
+            assert!(load(std::str::from_utf8(&buffer).unwrap()).unwrap().1.is_ok());
+        }
+    }
+
+    #[test]
+    fn test_rejected_snapshots() {
+        let mut future: Value = serde_json::from_str(V3).unwrap();
+        future["schema_version"] = json!(SCHEMA_VERSION + 1);
+        assert!(matches!(load(&future.to_string()), Err(SnapshotError::VersionMismatch { found, .. }) if found == SCHEMA_VERSION + 1));
+
+        let mut mismatched: Value = serde_json::from_str(V3).unwrap();
+        mismatched["dimensions"]["rows"] = json!(5);
+        assert!(matches!(load(&mismatched.to_string()), Err(SnapshotError::Invalid(_))));
+
+        assert!(matches!(load("[1, 2, 3]"), Err(SnapshotError::Invalid(_))));
+    }
+
+    // Sets every copy of the dimensions, so only their size can be rejected
+    fn set_dimensions(value: &mut Value, dimensions: &Value) {
+        match value {
+            Value::Object(object) => for (key, field) in object.iter_mut() {
+                if key == "dimensions" {
+                    *field = dimensions.clone();
+                } else {
+                    set_dimensions(field, dimensions);
+                }
+            },
+            Value::Array(items) => items.iter_mut().for_each(|item| set_dimensions(item, dimensions)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_dimensions() {
+        let huge = usize::MAX / 2;
+        for dimensions in [json!({"rows": 0, "shelves": 3, "zones": 4}), json!({"rows": huge, "shelves": huge, "zones": 1})] {
+            let mut snapshot: Value = serde_json::from_str(V3).unwrap();
+            set_dimensions(&mut snapshot, &dimensions);
+            assert!(matches!(load(&snapshot.to_string()), Err(SnapshotError::Invalid(_))));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use serde_derive::{Deserialize, Serialize};
+use time::UtcDateTime;
+use crate::coords::StoreCoords;
+use crate::journal::{JournalEvent, JournalSink};
+use super::{IndexPositions, ModificationError, Product, Warehouse};
+
+/// A single mutation of the warehouse, with enough data to undo it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WarehouseChange<I> {
+    Added {
+        coords: StoreCoords,
+        product: I,
+        // Where to put the product in the indices when undoing a removal, appended otherwise
+        #[serde(skip)]
+        positions: Option<IndexPositions>,
+    },
+    Removed {
+        coords: StoreCoords,
+        product: I,
+        #[serde(skip)]
+        positions: IndexPositions,
+    },
+    Moved {
+        from: StoreCoords,
+        to: StoreCoords,
+        #[serde(skip)]
+        positions: IndexPositions,
+        // Same as for Added, when undoing a move
+        #[serde(skip)]
+        to_positions: Option<IndexPositions>,
+    },
+    AmountChanged {
+        coords: StoreCoords,
+        before: u64,
+        after: u64,
+    },
+}
+
+impl<I: Product> WarehouseChange<I> {
+    /// The change that undoes this one
+    pub fn inverse(&self) -> WarehouseChange<I> {
+        match self {
+            WarehouseChange::Added { coords, product, .. } => WarehouseChange::Removed {
+                coords: coords.clone(),
+                product: product.clone(),
+                positions: IndexPositions::default(),
+            },
+            WarehouseChange::Removed { coords, product, positions } => WarehouseChange::Added {
+                coords: coords.clone(),
+                product: product.clone(),
+                positions: Some(positions.clone()),
+            },
+            WarehouseChange::Moved { from, to, positions, .. } => WarehouseChange::Moved {
+                from: to.clone(),
+                to: from.clone(),
+                positions: IndexPositions::default(),
+                to_positions: Some(positions.clone()),
+            },
+            WarehouseChange::AmountChanged { coords, before, after } => WarehouseChange::AmountChanged {
+                coords: coords.clone(),
+                before: *after,
+                after: *before,
+            },
+        }
+    }
+}
+
+// Value returned by a transaction, with its changes and the identifiers it picked
+type Committed<T, I> = (T, Vec<WarehouseChange<I>>, Vec<i64>);
+
+impl<I: Product> Warehouse<I> {
+    /// Runs the closure as a single all or nothing operation
+    /// If it returns an error every change it made is reverted, leaving the store, indices and free map as they were
+    /// Allocator state is not part of the warehouse and is not reverted
+    pub fn transaction<T, E>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, E>) -> Result<T, E> {
+        self.transaction_with_changes(f).map(|(value, _, _)| value)
+    }
+
+    /// Same as transaction, also handing back the committed changes in order and the identifiers picked
+    pub fn transaction_with_changes<T, E>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, E>) -> Result<Committed<T, I>, E> {
+        let outer = self.pending_changes.replace(Vec::new());
+        let outer_picks = self.pending_picks.replace(Vec::new());
+        let result = f(self);
+        let changes = self.pending_changes.take().expect("Transaction log is only taken here");
+        let picks = self.pending_picks.take().expect("Transaction picks are only taken here");
+
+        match (result, outer) {
+            (Ok(value), Some(mut outer)) => {
+                // Nested transactions are only committed with the outer one
+                outer.extend(changes.iter().cloned());
+                self.pending_changes = Some(outer);
+                self.pending_picks = outer_picks.map(|mut outer| { outer.extend(picks.iter().copied()); outer });
+                Ok((value, changes, picks))
+            }
+            (Ok(value), None) => {
+                for change in &changes {
+                    self.emit(change.clone());
+                }
+                for identifier in &picks {
+                    self.count_pick(*identifier);
+                }
+                Ok((value, changes, picks))
+            }
+            (Err(e), outer) => {
+                self.pending_changes = outer;
+                self.pending_picks = outer_picks;
+                for change in changes.into_iter().rev() {
+                    self.revert(change);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub(super) fn is_recording(&self) -> bool {
+        self.pending_changes.is_some() || self.journal.is_some()
+    }
+
+    // Changes inside a transaction are only journaled once it commits
+    pub(super) fn record(&mut self, change: WarehouseChange<I>) {
+        match &mut self.pending_changes {
+            Some(changes) => changes.push(change),
+            None => self.emit(change),
+        }
+    }
+
+    fn emit(&mut self, change: WarehouseChange<I>) {
+        if let Some(journal) = &mut self.journal {
+            let event = JournalEvent {
+                timestamp: UtcDateTime::now(),
+                operator: self.operator.clone(),
+                change,
+            };
+            if let Err(e) = journal.append(&event) {
+                eprintln!("Failed to write to journal: {}", e);
+            }
+        }
+    }
+
+    /// Every committed change is sent to the sink from now on
+    pub fn attach_journal(&mut self, journal: Box<dyn JournalSink<I>>) {
+        self.journal = Some(journal);
+    }
+
+    pub fn detach_journal(&mut self) -> Option<Box<dyn JournalSink<I>>> {
+        self.journal.take()
+    }
+
+    /// Moves the journal and operator onto a warehouse replacing this one, so the session keeps being journaled
+    pub fn hand_over_journal(&mut self, to: &mut Warehouse<I>) {
+        to.journal = self.journal.take();
+        to.operator = self.operator.take();
+    }
+
+    /// Name recorded in journal events as responsible for the changes
+    pub fn set_operator(&mut self, operator: Option<String>) {
+        self.operator = operator;
+    }
+
+    /// Applies a change as it originally happened, skipping filters and allocators
+    pub fn apply_change(&mut self, change: &WarehouseChange<I>) -> Result<(), ModificationError> {
+        match change {
+            WarehouseChange::Added { coords, product, positions } => {
+                self.check_placement(product, coords)?;
+                self.place_product(product.clone(), coords.clone(), positions.as_ref());
+                self.record(change.clone());
+            }
+            WarehouseChange::Removed { coords, product, .. } => {
+                // Make sure the journal and the store agree on what is being removed
+                if self.product_ref(coords)?.identifier() != product.identifier() {
+                    return Err(ModificationError::NotFound);
+                }
+                // Replaying or undoing a change is not a pick, so it is kept out of the pick counts
+                let (product, positions) = self.take_product(coords)?;
+                self.record(WarehouseChange::Removed { coords: coords.clone(), product, positions });
+            }
+            WarehouseChange::Moved { from, to, to_positions, .. } => {
+                self.relocate(from.clone(), to.clone(), to_positions.as_ref())?;
+            }
+            WarehouseChange::AmountChanged { coords, after, .. } => {
+                let product = self.product_mut(coords)?;
+                let before = product.amount();
+                if *after == 0 {
+                    return Err(ModificationError::InvalidAmount);
+                }
+                product.set_amount(*after);
+                self.record(WarehouseChange::AmountChanged { coords: coords.clone(), before, after: *after });
+            }
+        }
+        Ok(())
+    }
+
+    // Applies the inverse of a change, which must be the latest one still in effect
+    fn revert(&mut self, change: WarehouseChange<I>) {
+        match change {
+            WarehouseChange::Added { coords, .. } => {
+                self.take_product(&coords).expect("Reverted change should match the store");
+            }
+            WarehouseChange::Removed { coords, product, positions } => {
+                self.place_product(product, coords, Some(&positions));
+            }
+            WarehouseChange::Moved { from, to, positions, .. } => {
+                let (product, _) = self.take_product(&to).expect("Reverted change should match the store");
+                self.place_product(product, from, Some(&positions));
+            }
+            WarehouseChange::AmountChanged { coords, before, .. } => {
+                self.product_mut(&coords).expect("Reverted change should match the store").set_amount(before);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::WarehouseDimensions;
+    use crate::warehouse::{ModificationError, ProductCategory, Warehouse};
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    // State of the warehouse, without the snapshot envelope and its creation time
+    fn snapshot(warehouse: &Warehouse<AnyOldProduct>) -> String {
+        serde_json::to_string_pretty(warehouse).unwrap()
+    }
+
+    fn filled_warehouse() -> Warehouse<AnyOldProduct> {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for (identifier, name) in [(1, "Bolts"), (2, "Nuts"), (1, "Bolts")] {
+            let product = AnyOldProduct::new(identifier, name.to_string(), 10, ProductCategory::Normal);
+            assert!(warehouse.add_product(product, &mut allocator).is_ok());
+        }
+        warehouse
+    }
+
+    #[test]
+    fn test_rollback_on_full() {
+        let mut warehouse = filled_warehouse();
+        let before = snapshot(&warehouse);
+
+        let result = warehouse.transaction(|tx| {
+            let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+            for identifier in 10..20 {
+                let product = AnyOldProduct::new(identifier, format!("Item {}", identifier), 1, ProductCategory::Normal);
+                tx.add_product(product, &mut allocator)?;
+            }
+            Ok::<_, ModificationError>(())
+        });
+
+        assert!(matches!(result, Err(ModificationError::Full)));
+        assert_eq!(snapshot(&warehouse), before);
+    }
+
+    #[test]
+    fn test_rollback_mixed_operations() {
+        let mut warehouse = filled_warehouse();
+        let before = snapshot(&warehouse);
+
+        let result = warehouse.transaction(|tx| {
+            tx.remove_product((0,0,0).into())?;
+            tx.move_product((0,0,2).into(), (0,1,3).into())?;
+            tx.pick(&(0,0,1).into(), 4)?;
+            tx.split_stack(&(0,0,1).into(), 2, &mut WarehouseAllocatorClosestFirstEfficient)?;
+            tx.pick(&(0,1,3).into(), 10)?;
+            tx.remove_product((0,0,2).into())
+        });
+
+        assert!(matches!(result, Err(ModificationError::NotFound)));
+        assert_eq!(snapshot(&warehouse), before);
+    }
+
+    #[test]
+    fn test_commit_and_nested_rollback() {
+        let mut warehouse = filled_warehouse();
+
+        let result = warehouse.transaction(|tx| {
+            tx.remove_product((0,0,1).into())?;
+            let inner = tx.transaction(|tx| {
+                tx.remove_product((0,0,0).into())?;
+                tx.remove_product((0,0,0).into())
+            });
+            assert!(inner.is_err());
+            Ok::<_, ModificationError>(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(warehouse.search_by_id(&2), None);
+        assert_eq!(warehouse.search_by_id(&1), Some(&vec![(0,0,0).into(), (0,0,2).into()]));
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use serde_derive::{Deserialize, Serialize};
+use crate::coords::StoreCoords;
+use crate::free_map::FreeMap;
+use super::{product_run, Product, Warehouse};
+
+/// How often a product is picked compared to the rest, A being the fastest movers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum VelocityClass {
+    A,
+    B,
+    C,
+}
+
+impl Display for VelocityClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Shares of all picks, in percent, taken by the A products and by the A and B products together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbcThresholds {
+    a: u8,
+    b: u8,
+}
+
+impl AbcThresholds {
+    /// Shares must not decrease and stay within 100
+    pub fn new(a: u8, b: u8) -> Option<Self> {
+        (a <= b && b <= 100).then_some(AbcThresholds { a, b })
+    }
+
+    // Products are classed by the share of picks taken by the faster ones before them
+    fn class(&self, faster_picks: u64, total_picks: u64) -> VelocityClass {
+        let share = u128::from(faster_picks) * 100;
+        if share < u128::from(self.a) * u128::from(total_picks) {
+            VelocityClass::A
+        } else if share < u128::from(self.b) * u128::from(total_picks) {
+            VelocityClass::B
+        } else {
+            VelocityClass::C
+        }
+    }
+}
+
+impl Default for AbcThresholds {
+    fn default() -> Self {
+        AbcThresholds { a: 80, b: 95 }
+    }
+}
+
+// Both shares separated by a comma, as in "80,95"
+impl FromStr for AbcThresholds {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (a, b) = s.split_once(',').ok_or("Expected the A and B shares separated by a comma")?;
+        let a = a.trim().parse().map_err(|_| "Shares must be percentages")?;
+        let b = b.trim().parse().map_err(|_| "Shares must be percentages")?;
+        AbcThresholds::new(a, b).ok_or("Shares must be increasing and at most 100")
+    }
+}
+
+/// A stack to move so the layout matches the velocity classes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReslotMove {
+    pub identifier: i64,
+    pub name: String,
+    pub class: VelocityClass,
+    pub from: StoreCoords,
+    pub to: StoreCoords,
+}
+
+impl<I: Product> Warehouse<I> {
+    /// Times the identifier was picked from or removed, picks undone with the history are taken back
+    pub fn pick_count(&self, identifier: &i64) -> u64 {
+        self.pick_counts.get(identifier).copied().unwrap_or(0)
+    }
+
+    /// Class of every identifier picked before or in stock, stock that was never picked being C
+    pub fn velocity_classes(&self, thresholds: &AbcThresholds) -> BTreeMap<i64, VelocityClass> {
+        let mut ranked: Vec<(i64, u64)> = self.pick_counts.iter().map(|(identifier, count)| (*identifier, *count)).collect();
+        ranked.sort_by_key(|(identifier, count)| (std::cmp::Reverse(*count), *identifier));
+        let total = ranked.iter().map(|(_, count)| count).sum();
+
+        let mut classes: BTreeMap<i64, VelocityClass> = self.store_index_by_id.keys().map(|identifier| (*identifier, VelocityClass::C)).collect();
+        let mut faster = 0;
+        for (identifier, count) in ranked {
+            classes.insert(identifier, thresholds.class(faster, total));
+            faster += count;
+        }
+        classes
+    }
+
+    /// Moves bringing A stacks as close to the start of the warehouse as the free space allows,
+    /// after moving C stacks in front of them to the back. B stacks stay where they are
+    /// Moves only count when they change shelf, and must be made in order
+    pub fn reslotting_plan(&self, thresholds: &AbcThresholds) -> Vec<ReslotMove> {
+        let classes = &self.velocity_classes(thresholds);
+        let mut stacks: Vec<(&StoreCoords, VelocityClass)> = self.store_index_by_id.iter()
+            .flat_map(|(identifier, stacks)| stacks.iter().map(move |coords| (coords, classes[identifier])))
+            .collect();
+        stacks.sort_by_key(|(coords, _)| *coords);
+        let Some(frontier) = stacks.iter().rev().find(|(_, class)| *class == VelocityClass::A).map(|(coords, _)| shelf_of(coords)) else {
+            return Vec::new();
+        };
+
+        // Planned on a copy of the free map, so every move sees the space left by the ones before it
+        let mut free_map = self.free_map.clone();
+        let mut plan = Vec::new();
+        let slow = stacks.iter().filter(|(coords, class)| *class == VelocityClass::C && shelf_of(coords) < frontier);
+        for (from, _) in slow {
+            let product = self.entry(from).expect_ref("Only Some values in map");
+            let (len, constraints) = product_run(product);
+            if let Some(to) = free_map.find_last_run(len, &constraints).filter(|to| shelf_of(to) > frontier) {
+                plan.push(self.plan_move(&mut free_map, from, to, len, VelocityClass::C));
+            }
+        }
+
+        let mut fast: Vec<&StoreCoords> = stacks.iter().filter(|(_, class)| *class == VelocityClass::A).map(|(coords, _)| *coords).collect();
+        fast.sort_by_key(|coords| std::cmp::Reverse(self.pick_count(self.entry(coords).expect_ref("Only Some values in map").identifier())));
+        for from in fast {
+            let product = self.entry(from).expect_ref("Only Some values in map");
+            let (len, constraints) = product_run(product);
+            if let Some(to) = free_map.find_run(len, &constraints).filter(|to| shelf_of(to) < shelf_of(from)) {
+                plan.push(self.plan_move(&mut free_map, from, to, len, VelocityClass::A));
+            }
+        }
+        plan
+    }
+
+    fn plan_move(&self, free_map: &mut FreeMap, from: &StoreCoords, to: StoreCoords, len: usize, class: VelocityClass) -> ReslotMove {
+        free_map.occupy_range(to.clone()..=StoreCoords(to.0, to.1, to.2 + len - 1));
+        free_map.free_range(from.clone()..=StoreCoords(from.0, from.1, from.2 + len - 1));
+        let product = self.entry(from).expect_ref("Only Some values in map");
+        ReslotMove {
+            identifier: *product.identifier(),
+            name: product.name().clone(),
+            class,
+            from: from.clone(),
+            to,
+        }
+    }
+
+    // Picks inside a transaction wait for it to commit, like the changes themselves
+    pub(super) fn count_pick(&mut self, identifier: i64) {
+        match &mut self.pending_picks {
+            Some(picks) => picks.push(identifier),
+            None => *self.pick_counts.entry(identifier).or_default() += 1,
+        }
+    }
+
+    /// Counts picks again when the changes that made them are redone
+    pub fn count_picks(&mut self, identifiers: &[i64]) {
+        for identifier in identifiers {
+            self.count_pick(*identifier);
+        }
+    }
+
+    /// Takes back picks whose changes were undone
+    pub fn uncount_picks(&mut self, identifiers: &[i64]) {
+        for identifier in identifiers {
+            if let Some(count) = self.pick_counts.get_mut(identifier) {
+                *count -= 1;
+                if *count == 0 {
+                    self.pick_counts.remove(identifier);
+                }
+            }
+        }
+    }
+}
+
+fn shelf_of(coords: &StoreCoords) -> (usize, usize) {
+    (coords.0, coords.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::WarehouseDimensions;
+    use crate::history::History;
+    use crate::warehouse::{ModificationError, ProductCategory};
+    use crate::AnyOldProduct;
+    use crate::allocators::WarehouseAllocatorClosestFirstEfficient;
+
+    fn stack(identifier: i64, amount: u64) -> AnyOldProduct {
+        AnyOldProduct::new(identifier, format!("Item {}", identifier), amount, ProductCategory::Normal)
+    }
+
+    #[test]
+    fn test_pick_counts() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 2, 4));
+        let mut history = History::new(10);
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for product in [stack(1, 10), stack(1, 10), stack(2, 5)] {
+            history.run(&mut warehouse, |w| w.add_product(product, &mut allocator)).unwrap();
+        }
+
+        warehouse.pick(&StoreCoords(0, 0, 0), 4).unwrap();
+        warehouse.pick(&StoreCoords(0, 0, 0), 6).unwrap();
+        warehouse.remove_product(StoreCoords(0, 0, 2)).unwrap();
+        warehouse.split_stack(&StoreCoords(0, 0, 1), 5, &mut allocator).unwrap();
+        assert_eq!((warehouse.pick_count(&1), warehouse.pick_count(&2)), (2, 1));
+
+        // Only committed transactions count, nested ones with the outer one
+        let result = warehouse.transaction(|w| {
+            w.transaction(|w| w.pick(&StoreCoords(0, 0, 1), 1))?;
+            w.pick(&StoreCoords(0, 0, 1), 100)
+        });
+        assert!(matches!(result, Err(ModificationError::InsufficientAmount { .. })));
+        assert_eq!(warehouse.pick_count(&1), 2);
+        warehouse.transaction(|w| w.transaction(|w| w.pick(&StoreCoords(0, 0, 1), 1))).unwrap();
+        assert_eq!(warehouse.pick_count(&1), 3);
+
+        // Undoing an add takes the stack out, without being a pick
+        history.clear();
+        history.run(&mut warehouse, |w| w.add_product(stack(3, 1), &mut allocator)).unwrap();
+        history.undo(&mut warehouse).unwrap();
+        assert_eq!(warehouse.pick_count(&3), 0);
+    }
+
+    #[test]
+    fn test_undone_picks_are_not_counted() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 2, 4));
+        let mut history = History::new(10);
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        history.run(&mut warehouse, |w| w.add_product(stack(1, 10), &mut allocator)).unwrap();
+        history.run(&mut warehouse, |w| w.add_product(stack(2, 10), &mut allocator)).unwrap();
+
+        history.run(&mut warehouse, |w| w.pick(&StoreCoords(0, 0, 0), 4)).unwrap();
+        history.run(&mut warehouse, |w| {
+            w.pick(&StoreCoords(0, 0, 0), 6)?;
+            w.remove_product(StoreCoords(0, 0, 1))
+        }).unwrap();
+        assert_eq!((warehouse.pick_count(&1), warehouse.pick_count(&2)), (2, 1));
+
+        history.undo(&mut warehouse).unwrap();
+        assert_eq!((warehouse.pick_count(&1), warehouse.pick_count(&2)), (1, 0));
+        history.undo(&mut warehouse).unwrap();
+        assert_eq!(warehouse.pick_count(&1), 0);
+        assert!(warehouse.velocity_classes(&AbcThresholds::default()).values().all(|class| *class == VelocityClass::C));
+
+        history.redo(&mut warehouse).unwrap();
+        history.redo(&mut warehouse).unwrap();
+        assert_eq!((warehouse.pick_count(&1), warehouse.pick_count(&2)), (2, 1));
+    }
+
+    #[test]
+    fn test_pick_counts_saved_in_snapshots() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 2, 4));
+        warehouse.add_product(stack(1, 10), &mut WarehouseAllocatorClosestFirstEfficient).unwrap();
+        warehouse.pick(&StoreCoords(0, 0, 0), 3).unwrap();
+
+        let mut json = Vec::new();
+        warehouse.to_json(&mut json).unwrap();
+        let (loaded, _) = Warehouse::<AnyOldProduct>::from_json(&mut json.as_slice()).unwrap();
+        assert_eq!(loaded.pick_count(&1), 1);
+    }
+
+    #[test]
+    fn test_velocity_classes() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 2, 4));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for product in [stack(1, 10), stack(2, 10), stack(3, 10), stack(4, 10)] {
+            warehouse.add_product(product, &mut allocator).unwrap();
+        }
+        for (coords, picks) in [(StoreCoords(0, 0, 0), 7), (StoreCoords(0, 0, 1), 2), (StoreCoords(0, 0, 2), 1)] {
+            for _ in 0..picks {
+                warehouse.pick(&coords, 1).unwrap();
+            }
+        }
+
+        // Item 2 starts at 70% of the picks, under the A share, while item 3 starts at 90%
+        let classes = warehouse.velocity_classes(&AbcThresholds::default());
+        assert_eq!(classes.into_iter().collect::<Vec<_>>(), vec![(1, VelocityClass::A), (2, VelocityClass::A), (3, VelocityClass::B), (4, VelocityClass::C)]);
+        let classes = warehouse.velocity_classes(&"50,75".parse().unwrap());
+        assert_eq!(classes.into_values().collect::<Vec<_>>(), vec![VelocityClass::A, VelocityClass::B, VelocityClass::C, VelocityClass::C]);
+
+        assert_eq!("90,80".parse::<AbcThresholds>(), Err("Shares must be increasing and at most 100"));
+        assert!("80".parse::<AbcThresholds>().is_err());
+        assert_eq!(AbcThresholds::new(80, 101), None);
+    }
+
+    #[test]
+    fn test_reslotting_plan() {
+        let mut warehouse = Warehouse::new(WarehouseDimensions::new(1, 4, 2));
+        let mut allocator = WarehouseAllocatorClosestFirstEfficient;
+        for product in [stack(4, 10), stack(3, 10)] {
+            warehouse.add_product(product, &mut allocator).unwrap();
+        }
+        warehouse.place_product(stack(1, 10), StoreCoords(0, 2, 0), None);
+        for _ in 0..8 {
+            warehouse.pick(&StoreCoords(0, 2, 0), 1).unwrap();
+        }
+        warehouse.pick(&StoreCoords(0, 0, 1), 1).unwrap();
+
+        // The slow item 4 leaves the front for the back, so the fast item 1 can take its place
+        let plan = warehouse.reslotting_plan(&AbcThresholds::default());
+        let moves: Vec<_> = plan.iter().map(|step| (step.identifier, step.class, step.from.clone(), step.to.clone())).collect();
+        assert_eq!(moves, vec![
+            (4, VelocityClass::C, StoreCoords(0, 0, 0), StoreCoords(0, 3, 1)),
+            (1, VelocityClass::A, StoreCoords(0, 2, 0), StoreCoords(0, 0, 0)),
+        ]);
+
+        for step in &plan {
+            warehouse.move_product(step.from.clone(), step.to.clone()).unwrap();
+        }
+        assert!(warehouse.reslotting_plan(&AbcThresholds::default()).is_empty());
+        assert!(warehouse.verify().is_ok());
+    }
+}